@@ -1,40 +1,234 @@
+use crate::config::{Canary, Drain, KeepPrevious, Route, Tls};
+use crate::git::{SyncError, Track, Update};
+use crate::history::{self, Kind, Outcome};
+use crate::paths::PathFilter;
+use crate::probe::{self, Probe};
 use crate::state::Slot;
 use crate::{docker, git, state, traefik};
-use ractor::{async_trait, Actor, ActorProcessingErr, ActorRef};
-use std::path::PathBuf;
-use std::time::Duration;
-use tracing::{error, info, warn};
+use anyhow::Context;
+use chrono::{DateTime, Utc};
+use ractor::{async_trait, Actor, ActorProcessingErr, ActorRef, RpcReplyPort};
+use serde::Serialize;
+use serde_json::json;
+use std::future::Future;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+use tracing::{error, info, warn, Instrument};
 
 pub struct Deployer;
 
+/// Generated compose overrides, relative to the checkout
+const OVERRIDE_DIR: &str = ".rollploy-compose";
+const SHARED_OVERRIDE: &str = ".rollploy-compose/shared.yml";
+const SLOT_OVERRIDE: &str = ".rollploy-compose/slot.yml";
+
+/// How often a live slot is checked during canary steps and the bake
+const WATCH_INTERVAL: Duration = Duration::from_secs(5);
+
 pub struct DeployerArgs {
+    pub app_name: String,
     pub repo_url: String,
-    pub branch: String,
+    pub git: git::Options,
     pub local_path: PathBuf,
+    pub include: Vec<String>,
+    pub exclude: Vec<String>,
     pub compose_file: String,
-    pub port: u16,
+    pub shared: Vec<String>,
+    pub entry: traefik::Entry,
+    pub traefik_image: String,
     pub interval: Duration,
     pub health_timeout: Duration,
+    pub probes: Vec<Probe>,
+    pub bake: Duration,
+    pub route: Option<Route>,
+    pub canary: Option<Canary>,
+    pub tls: Option<Tls>,
+    pub drain: Option<Drain>,
+    pub keep_previous: KeepPrevious,
+    pub retention: Duration,
+    pub history_limit: usize,
 }
 
-pub struct State {
-    repo_url: String,
+/// Settings a rollout needs, shared with the task running it
+struct App {
+    name: String,
+    git: git::Options,
     local_path: PathBuf,
     compose_file: String,
-    active_slot: Slot,
-    health_timeout: Duration,
-    app_name: String,
+    /// Compose services run once in the shared project instead of per slot
+    shared: Vec<String>,
     network: String,
+    health_timeout: Duration,
+    probes: Vec<Probe>,
+    bake: Duration,
+    route: Option<Route>,
+    canary: Option<Canary>,
+    drain: Option<Drain>,
+    keep_previous: KeepPrevious,
+    retention: Duration,
+    proxy: traefik::Proxy,
 }
 
-#[derive(Debug, Clone)]
+pub struct State {
+    app: Arc<App>,
+    repo_url: String,
+    paths: PathFilter,
+    active_slot: Slot,
+    history_limit: usize,
+    deployed_sha: Option<String>,
+    previous_sha: Option<String>,
+    /// Until when the inactive slot keeps `previous_sha`; None if it is empty
+    warm_until: Option<DateTime<Utc>>,
+    last_deploy: Option<DeployResult>,
+    last_poll: Option<DateTime<Utc>>,
+    /// Why the last pull failed, e.g. a diverged branch
+    sync_error: Option<String>,
+    paused: bool,
+    /// The rollout running in the background, if any
+    rollout: Option<Running>,
+    /// A request that arrived during a rollout, replayed once it ends
+    queued: Option<Message>,
+}
+
+#[derive(Debug)]
 pub enum Message {
     Tick,
+    /// Pull and roll out immediately, even without new commits
+    DeployNow,
+    /// Stop reacting to `Tick` until resumed
+    Pause,
+    Resume,
+    /// Redeploy the previously deployed commit into the inactive slot
+    Rollback,
+    /// Cancel the running rollout, tearing down the slot it was starting or
+    /// bringing the old slot back during the bake
+    Abort,
+    Status(RpcReplyPort<Status>),
+    /// Sent by the background rollout when traffic moves to a slot
+    Switched(Slot),
+    /// Sent by the background rollout when it ends; the outcome is the
+    /// result of its task
+    Finished,
+}
+
+/// Snapshot of a deployer, as reported by the control API
+#[derive(Debug, Clone, Serialize)]
+pub struct Status {
+    pub app: String,
+    pub repo: String,
+    pub active_slot: Slot,
+    pub deployed_sha: Option<String>,
+    pub previous_sha: Option<String>,
+    pub warm_until: Option<DateTime<Utc>>,
+    pub last_deploy: Option<DeployResult>,
+    pub last_poll: Option<DateTime<Utc>>,
+    pub sync_error: Option<String>,
+    pub paused: bool,
+    pub rollout: Option<Attempt>,
 }
 
-impl State {
+#[derive(Debug, Clone, Serialize)]
+pub struct DeployResult {
+    pub finished_at: DateTime<Utc>,
+    pub sha: Option<String>,
+    pub error: Option<String>,
+}
+
+/// A rollout being attempted, recorded in status and history when it ends
+#[derive(Debug, Clone, Serialize)]
+pub struct Attempt {
+    kind: Kind,
+    sha: Option<String>,
+    slot: Slot,
+    started_at: DateTime<Utc>,
+}
+
+/// A rollout running in the background
+struct Running {
+    attempt: Attempt,
+    /// Tracked revision the rollout started from; newer commits supersede it.
+    /// None for rollbacks, which always run to the end.
+    tracked: Option<String>,
+    /// Holds the reason once the rollout is cancelled
+    cancel: watch::Sender<Option<Cancel>>,
+    /// Slot serving traffic as last reported by the rollout
+    live: watch::Receiver<Slot>,
+    task: JoinHandle<anyhow::Result<()>>,
+    /// Deployed and previous commits from before the switch, restored if the
+    /// bake fails. Some once the new slot is live.
+    replaced: Option<(Option<String>, Option<String>)>,
+    /// Whether the new slot went live and the old one was brought back
+    reverted: bool,
+}
+
+/// Why a rollout is cancelled
+#[derive(Debug, Clone, PartialEq)]
+enum Cancel {
+    /// Undo the rollout, e.g. for an operator abort or a newer commit
+    Revert(String),
+    /// The deployer is stopping; a new slot that already took over stays
+    Stop,
+}
+
+impl std::fmt::Display for Cancel {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Cancel::Revert(reason) => f.write_str(reason),
+            Cancel::Stop => f.write_str("deployer stopped"),
+        }
+    }
+}
+
+/// How a background rollout reports back to its deployer
+struct Report {
+    myself: ActorRef<Message>,
+    /// Also kept outside the mailbox, for when the deployer stops mid-rollout
+    live: watch::Sender<Slot>,
+}
+
+impl Report {
+    fn switched(&self, slot: Slot) {
+        self.live.send_replace(slot);
+        let _ = self.myself.send_message(Message::Switched(slot));
+    }
+}
+
+/// Returned by a rollout that was stopped before it switched slots, or
+/// whose bake was aborted
+#[derive(Debug, thiserror::Error)]
+#[error("cancelled: {0}")]
+struct Cancelled(String);
+
+impl Attempt {
+    fn new(kind: Kind, sha: Option<String>, slot: Slot) -> Self {
+        Self {
+            kind,
+            sha,
+            slot,
+            started_at: Utc::now(),
+        }
+    }
+}
+
+impl App {
     fn project_name(&self, slot: Slot) -> String {
-        format!("{}-{}", self.app_name, slot.as_str())
+        format!("{}-{}", self.name, slot.as_str())
+    }
+
+    fn shared_project(&self) -> String {
+        format!("{}-shared", self.name)
+    }
+
+    /// Compose files of the slot projects, relative to the checkout
+    fn slot_files(&self) -> Vec<&str> {
+        let mut files = vec![self.compose_file.as_str()];
+        if !self.shared.is_empty() {
+            files.push(SLOT_OVERRIDE);
+        }
+        files
     }
 }
 
@@ -49,43 +243,95 @@ impl Actor for Deployer {
         myself: ActorRef<Self::Msg>,
         args: Self::Arguments,
     ) -> Result<Self::State, ActorProcessingErr> {
-        info!(repo = %args.repo_url, port = args.port, "starting deployer");
+        info!(repo = %args.repo_url, track = %args.git.track, entry = %args.entry, "starting deployer");
 
-        // Derive app name from repo
-        let app_name = args
-            .repo_url
-            .split('/')
-            .last()
-            .unwrap_or("app")
-            .trim_end_matches(".git")
-            .to_string();
+        let app_name = args.app_name;
+        let network = docker::network_name(&app_name);
 
-        let network = format!("rollploy-{}", app_name);
+        // Clone repo
+        git::ensure_repo(&args.local_path, &args.repo_url, &args.git).await?;
 
         // Setup infrastructure
-        docker::create_network(&network)?;
-        traefik::start(&app_name, args.port, &network)?;
-
-        // Clone repo
-        git::ensure_repo(&args.local_path, &args.repo_url, &args.branch)?;
+        let proxy =
+            traefik::Proxy::new(&app_name, &args.local_path, &args.entry, args.tls.as_ref())?;
+        docker::create_network(&network).await?;
+        match args.entry {
+            traefik::Entry::Port(port) => {
+                traefik::reconcile(
+                    &app_name,
+                    &args.traefik_image,
+                    port,
+                    &network,
+                    &args.local_path.join(traefik::DYNAMIC_DIR),
+                    args.tls.as_ref(),
+                )
+                .await?;
+                // Routes left behind from when rollploy owned routing
+                if args.route.is_none() {
+                    traefik::clear_routes(&proxy)?;
+                }
+            }
+            traefik::Entry::Edge { .. } => {
+                traefik::join_edge(&network).await?;
+                traefik::remove(&app_name).await?;
+            }
+        }
 
         // Load persisted state
         let persisted = state::load(&args.local_path).unwrap_or_default();
 
-        let state = State {
+        let mut state = State {
+            app: Arc::new(App {
+                name: app_name,
+                git: args.git,
+                local_path: args.local_path,
+                compose_file: args.compose_file,
+                shared: args.shared,
+                network,
+                health_timeout: args.health_timeout,
+                probes: args.probes,
+                bake: args.bake,
+                route: args.route,
+                canary: args.canary,
+                drain: args.drain,
+                keep_previous: args.keep_previous,
+                retention: args.retention,
+                proxy,
+            }),
             repo_url: args.repo_url,
-            local_path: args.local_path,
-            compose_file: args.compose_file,
+            paths: PathFilter::new(&args.include, &args.exclude)?,
             active_slot: persisted.active_slot,
-            health_timeout: args.health_timeout,
-            app_name,
-            network,
+            history_limit: args.history_limit,
+            deployed_sha: persisted.deployed_sha,
+            previous_sha: persisted.previous_sha,
+            warm_until: persisted.warm_until,
+            last_deploy: None,
+            last_poll: None,
+            sync_error: None,
+            paused: false,
+            rollout: None,
+            queued: None,
         };
 
-        // Initial deploy
-        if let Err(e) = deploy(&state, state.active_slot) {
-            error!(error = %e, "initial deploy failed");
+        // Catch up with the tracked revision (e.g. a newly pinned commit)
+        let moved = sync(&mut state).await.unwrap_or(false);
+
+        // Initial deploy, keeping the deployed commit unless the target moved
+        let head = git::head(&state.app.local_path).await?;
+        let sha = match &state.deployed_sha {
+            Some(deployed) if !moved => deployed.clone(),
+            _ => head.clone(),
+        };
+        if sha != head {
+            info!(sha = %sha, "restoring previously deployed commit");
+            // e.g. a rollout cut short by a restart
+            if !deployed_deliberately(&state.app.local_path) {
+                info!(sha = %head, "checked out commit was never rolled out, deploying it next");
+                state.queued = Some(Message::DeployNow);
+            }
         }
+        let attempt = Attempt::new(Kind::Initial, Some(sha), state.active_slot);
+        start(&myself, &mut state, attempt, Some(head));
 
         myself.send_interval(args.interval, || Message::Tick);
 
@@ -94,81 +340,839 @@ impl Actor for Deployer {
 
     async fn handle(
         &self,
-        _myself: ActorRef<Self::Msg>,
+        myself: ActorRef<Self::Msg>,
         message: Self::Msg,
         state: &mut Self::State,
     ) -> Result<(), ActorProcessingErr> {
         match message {
             Message::Tick => {
+                if state.paused {
+                    info!(repo = %state.repo_url, "polling paused, skipping");
+                    return Ok(());
+                }
+                // The worktree belongs to the rollout until it ends
+                if state.rollout.is_some() {
+                    supersede(state).await;
+                    return Ok(());
+                }
+                expire_previous(state).await;
                 info!(repo = %state.repo_url, "checking for updates");
-                match git::pull(&state.local_path) {
-                    Ok(true) => {
+                state.last_poll = Some(Utc::now());
+                match sync(state).await {
+                    Some(true) => {
                         info!(repo = %state.repo_url, "updates found, deploying");
-                        if let Err(e) = blue_green_deploy(state) {
-                            error!(error = %e, "deploy failed");
-                        }
+                        rollout(&myself, state).await;
                     }
-                    Ok(false) => {
+                    Some(false) => {
                         info!(repo = %state.repo_url, "no updates");
                     }
-                    Err(e) => {
-                        error!(error = %e, "git pull failed");
-                    }
+                    None => {}
+                }
+            }
+            Message::DeployNow | Message::Rollback if state.rollout.is_some() => {
+                info!(repo = %state.repo_url, request = ?message, "rollout in progress, queued");
+                state.queued = Some(message);
+            }
+            Message::DeployNow => {
+                info!(repo = %state.repo_url, "deploy requested");
+                state.last_poll = Some(Utc::now());
+                sync(state).await;
+                rollout(&myself, state).await;
+            }
+            Message::Pause => {
+                info!(repo = %state.repo_url, "pausing polling");
+                state.paused = true;
+            }
+            Message::Resume => {
+                info!(repo = %state.repo_url, "resuming polling");
+                state.paused = false;
+            }
+            Message::Rollback => {
+                let Some(sha) = state.previous_sha.clone() else {
+                    warn!(repo = %state.repo_url, "no previous deployment to roll back to");
+                    return Ok(());
+                };
+                info!(repo = %state.repo_url, sha = %sha, "rolling back");
+                let attempt = Attempt::new(Kind::Rollback, Some(sha), state.active_slot.other());
+                start(&myself, state, attempt, None);
+            }
+            Message::Abort => {
+                if state.rollout.is_none() {
+                    warn!(repo = %state.repo_url, "no rollout to abort");
+                    return Ok(());
+                }
+                info!(repo = %state.repo_url, "aborting rollout");
+                state.queued = None;
+                cancel(state, "aborted by operator".to_string());
+            }
+            Message::Status(reply) => {
+                let _ = reply.send(Status {
+                    app: state.app.name.clone(),
+                    repo: state.repo_url.clone(),
+                    active_slot: state.active_slot,
+                    deployed_sha: state.deployed_sha.clone(),
+                    previous_sha: state.previous_sha.clone(),
+                    warm_until: state.warm_until,
+                    last_deploy: state.last_deploy.clone(),
+                    last_poll: state.last_poll,
+                    sync_error: state.sync_error.clone(),
+                    paused: state.paused,
+                    rollout: state.rollout.as_ref().map(|r| r.attempt.clone()),
+                });
+            }
+            Message::Switched(slot) => switched(state, slot),
+            Message::Finished => {
+                let Some(running) = &mut state.rollout else {
+                    return Ok(());
+                };
+                let result = join(&mut running.task).await;
+                finished(state, result);
+                if let Some(message) = state.queued.take() {
+                    myself.send_message(message)?;
                 }
             }
         }
         Ok(())
     }
+
+    /// Cancel a running rollout and wait for it to clean up, so it doesn't
+    /// race a replacement deployer on the same checkout
+    async fn post_stop(
+        &self,
+        _myself: ActorRef<Self::Msg>,
+        state: &mut Self::State,
+    ) -> Result<(), ActorProcessingErr> {
+        let Some(running) = &mut state.rollout else {
+            return Ok(());
+        };
+        info!(repo = %state.repo_url, "deployer stopping, waiting for the rollout to wind down");
+        running.cancel.send_replace(Some(Cancel::Stop));
+        let result = join(&mut running.task).await;
+        // Switches the mailbox no longer delivers
+        let live = *running.live.borrow();
+        if live != state.active_slot {
+            switched(state, live);
+        }
+        finished(state, result);
+        Ok(())
+    }
+}
+
+async fn join(task: &mut JoinHandle<anyhow::Result<()>>) -> anyhow::Result<()> {
+    task.await
+        .unwrap_or_else(|e| Err(anyhow::anyhow!("rollout task failed: {}", e)))
+}
+
+/// Traffic moved to `slot` during the running rollout
+fn switched(state: &mut State, slot: Slot) {
+    let Some(running) = &mut state.rollout else {
+        return;
+    };
+    if slot == running.attempt.slot {
+        // Live from here on, so a restart keeps the new commit
+        running.replaced = Some((state.deployed_sha.clone(), state.previous_sha.clone()));
+        let sha = running.attempt.sha.clone();
+        if sha.is_some() && state.deployed_sha != sha {
+            state.previous_sha = std::mem::replace(&mut state.deployed_sha, sha);
+        }
+    } else if let Some((deployed, previous)) = running.replaced.take() {
+        state.deployed_sha = deployed;
+        state.previous_sha = previous;
+        running.reverted = true;
+    }
+    info!(active = %slot.as_str(), "switched slots");
+    state.active_slot = slot;
+    persist(state);
+}
+
+/// The running rollout ended with `result`
+fn finished(state: &mut State, result: anyhow::Result<()>) {
+    let Some(Running {
+        attempt, reverted, ..
+    }) = state.rollout.take()
+    else {
+        return;
+    };
+    match &result {
+        Ok(()) => {
+            state.active_slot = attempt.slot;
+            if attempt.kind != Kind::Initial && state.app.keep_previous != KeepPrevious::Down {
+                state.warm_until = Some(Utc::now() + state.app.retention);
+            }
+        }
+        Err(e) if e.is::<Cancelled>() => {
+            info!(reason = %e, kind = ?attempt.kind, "rollout cancelled")
+        }
+        Err(e) => {
+            error!(error = %format!("{:#}", e), kind = ?attempt.kind, "rollout failed")
+        }
+    }
+    record(state, attempt, result, reverted);
+}
+
+/// Pull the tracked revision, keeping the failure in state for status reporting.
+/// Returns whether relevant files changed, or None if the pull failed.
+async fn sync(state: &mut State) -> Option<bool> {
+    match git::pull(&state.app.local_path, &state.app.git).await {
+        Ok(update) => {
+            state.sync_error = None;
+            Some(update.is_some_and(|update| needs_rollout(state, &update)))
+        }
+        Err(e) => {
+            if e.downcast_ref::<SyncError>().is_some() {
+                error!(error = %e, "checkout needs attention, not deploying until it is fixed");
+            } else {
+                error!(error = %e, "git pull failed");
+            }
+            state.sync_error = Some(format!("{:#}", e));
+            None
+        }
+    }
 }
 
-fn deploy(state: &State, slot: Slot) -> anyhow::Result<()> {
-    let project = state.project_name(slot);
-    let files = [state.compose_file.as_str()];
+/// Whether the last rollout left the deployed commit on purpose: a rollback,
+/// or a newer commit taken back out after its bake
+fn deployed_deliberately(dir: &Path) -> bool {
+    let entries = history::load(dir).unwrap_or_default();
+    entries
+        .iter()
+        .rev()
+        .find(|entry| entry.kind != Kind::Initial)
+        .is_some_and(|entry| entry.kind == Kind::Rollback || entry.reverted)
+}
+
+/// Check the changed files of an update against the include/exclude globs
+fn needs_rollout(state: &State, update: &Update) -> bool {
+    let relevant = state.paths.relevant(&update.files);
+    if relevant.is_empty() {
+        info!(
+            from = %update.from,
+            to = %update.to,
+            files = update.files.len(),
+            "no relevant files changed, skipping rollout"
+        );
+        return false;
+    }
+    info!(
+        from = %update.from,
+        to = %update.to,
+        files = %relevant.join(", "),
+        "relevant files changed"
+    );
+    true
+}
+
+/// Cancel the running rollout if a newer relevant commit arrived, queueing a
+/// deploy of the latest revision in its place
+async fn supersede(state: &mut State) {
+    let Some(running) = &state.rollout else {
+        return;
+    };
+    // The next poll after the bake picks up newer commits
+    if running.replaced.is_some() {
+        info!(repo = %state.repo_url, "new slot live, skipping poll until the rollout ends");
+        return;
+    }
+    let Some(tracked) = running.tracked.clone() else {
+        info!(repo = %state.repo_url, "rollback in progress, skipping poll");
+        return;
+    };
+    info!(repo = %state.repo_url, "rollout in progress, checking for newer commits");
+    state.last_poll = Some(Utc::now());
+    let update = match git::peek(&state.app.local_path, &state.app.git, &tracked).await {
+        Ok(Some(update)) => update,
+        Ok(None) => return,
+        Err(e) => {
+            warn!(error = %e, "failed to check for newer commits");
+            return;
+        }
+    };
+    if !needs_rollout(state, &update) {
+        return;
+    }
+    info!(sha = %update.to, "newer commit arrived, restarting rollout");
+    let short = update.to.get(..7).unwrap_or(&update.to);
+    cancel(state, format!("superseded by {}", short));
+    state.queued = Some(Message::DeployNow);
+}
+
+/// Remove the kept old slot once its retention window has passed
+async fn expire_previous(state: &mut State) {
+    if state.warm_until.is_none_or(|until| until > Utc::now()) {
+        return;
+    }
+    let project = state.app.project_name(state.active_slot.other());
+    info!(project = %project, "retention passed, removing previous slot");
+    let files = state.app.slot_files();
+    if let Err(e) = docker::compose_down(&state.app.local_path, &files, &project).await {
+        // Tried again on the next poll
+        warn!(error = %e, "failed to remove previous slot");
+        return;
+    }
+    state.warm_until = None;
+    persist(state);
+}
+
+/// Signal the running rollout to stop; it reports back through [`Message::Finished`]
+fn cancel(state: &mut State, reason: String) {
+    if let Some(running) = &mut state.rollout {
+        // Further polls have nothing to supersede
+        running.tracked = None;
+        running.cancel.send_replace(Some(Cancel::Revert(reason)));
+    }
+}
+
+/// Blue-green deploy the current checkout
+async fn rollout(myself: &ActorRef<Message>, state: &mut State) {
+    let head = git::head(&state.app.local_path).await.ok();
+    let attempt = Attempt::new(Kind::Deploy, head.clone(), state.active_slot.other());
+    start(myself, state, attempt, head);
+}
+
+/// Run `attempt` in a background task so the actor keeps answering messages;
+/// [`Message::Finished`] tells it to collect the outcome. `tracked` is the checked
+/// out revision of the tracked ref, or None for a rollback; the worktree is
+/// moved to the attempt's commit for the duration if it differs.
+fn start(myself: &ActorRef<Message>, state: &mut State, attempt: Attempt, tracked: Option<String>) {
+    let app = state.app.clone();
+    let from = state.active_slot;
+    let (kind, sha, slot) = (attempt.kind, attempt.sha.clone(), attempt.slot);
+    let prepare = match (kind, state.warm_until.is_some()) {
+        (Kind::Initial, _) => Prepare::Fresh,
+        (Kind::Rollback, true) => Prepare::Reuse,
+        (Kind::Deploy, true) => Prepare::Replace,
+        (_, false) => Prepare::Fresh,
+    };
+    // The rollout takes over the inactive slot, whatever it ends with
+    if kind != Kind::Initial {
+        state.warm_until = None;
+    }
+    let checkout = sha != tracked;
+    let (cancel, cancelled) = watch::channel(None);
+    let (live, live_rx) = watch::channel(from);
+    let report = Report {
+        myself: myself.clone(),
+        live,
+    };
+
+    // Keep the actor's span so rollout logs stay attributed to the app
+    let task = tokio::spawn(
+        async move {
+            let run = async {
+                match kind {
+                    // The active slot keeps whatever got started
+                    Kind::Initial => {
+                        let started = async {
+                            deploy(&app, slot).await?;
+                            route_to(&app, slot).await
+                        };
+                        cancellable(started, cancelled).await
+                    }
+                    Kind::Deploy | Kind::Rollback => {
+                        blue_green_deploy(&report, &app, from, slot, prepare, cancelled).await
+                    }
+                }
+            };
+            let result = match sha.filter(|_| checkout) {
+                Some(sha) => with_checkout(&app, &sha, run).await,
+                None => run.await,
+            };
+            let _ = report.myself.send_message(Message::Finished);
+            result
+        }
+        .in_current_span(),
+    );
+
+    state.rollout = Some(Running {
+        attempt,
+        tracked,
+        cancel,
+        live: live_rx,
+        task,
+        replaced: None,
+        reverted: false,
+    });
+}
+
+/// How a blue-green rollout gets the containers of the new slot
+#[derive(Debug, Clone, Copy)]
+enum Prepare {
+    /// Start them in the empty slot
+    Fresh,
+    /// Remove the previous commit kept in the slot first
+    Replace,
+    /// Start the previous commit kept in the slot, for a fast rollback
+    Reuse,
+}
+
+/// Run `f` until it finishes or the rollout is cancelled
+async fn cancellable<T>(
+    f: impl Future<Output = anyhow::Result<T>>,
+    mut cancel: watch::Receiver<Option<Cancel>>,
+) -> anyhow::Result<T> {
+    tokio::select! {
+        result = f => result,
+        // A dropped sender disables this branch, leaving `f` to finish
+        Ok(reason) = cancel.wait_for(Option::is_some) => {
+            let reason = reason.as_ref().map(Cancel::to_string).unwrap_or_default();
+            Err(Cancelled(reason).into())
+        }
+    }
+}
+
+/// Run `f` with the worktree at `sha`, then return to the tracked revision so
+/// the next pull only sees genuinely new commits
+async fn with_checkout(
+    app: &App,
+    sha: &str,
+    f: impl Future<Output = anyhow::Result<()>>,
+) -> anyhow::Result<()> {
+    let restore = match &app.git.track {
+        Track::Branch(branch) => branch.clone(),
+        Track::Tag(_) | Track::Commit(_) => git::head(&app.local_path).await?,
+    };
+    git::checkout(&app.local_path, sha, &app.git).await?;
+    let result = f.await;
+    if let Err(e) = git::checkout(&app.local_path, &restore, &app.git).await {
+        error!(error = %e, rev = %restore, "failed to return to tracked revision");
+    }
+    result
+}
+
+/// Remember the outcome of a rollout, persist the deployed commits and
+/// append it to the deploy history
+fn record(state: &mut State, attempt: Attempt, result: anyhow::Result<()>, reverted: bool) {
+    let sha = attempt.sha;
+    if result.is_ok() && sha.is_some() && state.deployed_sha != sha {
+        state.previous_sha = std::mem::replace(&mut state.deployed_sha, sha.clone());
+    }
+    let finished_at = Utc::now();
+    let error = result.as_ref().err().map(|e| format!("{:#}", e));
+    state.last_deploy = Some(DeployResult {
+        finished_at,
+        sha: sha.clone(),
+        error: error.clone(),
+    });
+
+    let entry = history::Entry {
+        sha,
+        slot: attempt.slot,
+        kind: attempt.kind,
+        started_at: attempt.started_at,
+        finished_at,
+        outcome: match &result {
+            Ok(()) => Outcome::Success,
+            Err(e) if e.is::<Cancelled>() => Outcome::Cancelled,
+            Err(_) => Outcome::Failure,
+        },
+        error,
+        reverted,
+    };
+    if let Err(e) = history::append(&state.app.local_path, &entry, state.history_limit) {
+        error!(error = %e, "failed to append deploy history");
+    }
+
+    persist(state);
+}
+
+fn persist(state: &State) {
+    let persisted = state::PersistedState {
+        active_slot: state.active_slot,
+        deployed_sha: state.deployed_sha.clone(),
+        previous_sha: state.previous_sha.clone(),
+        warm_until: state.warm_until,
+    };
+    if let Err(e) = state::save(&state.app.local_path, &persisted) {
+        error!(error = %e, "failed to save state");
+    }
+}
+
+async fn deploy(app: &App, slot: Slot) -> anyhow::Result<()> {
+    let project = app.project_name(slot);
+    let services = if app.shared.is_empty() {
+        Vec::new()
+    } else {
+        start_shared(app).await?
+    };
 
     info!(project = %project, "deploying");
-    docker::compose_up(&state.local_path, &files, &project, &state.network)?;
+    docker::compose_up(
+        &app.local_path,
+        &app.slot_files(),
+        &project,
+        &app.network,
+        &services,
+        "always",
+    )
+    .await?;
 
     Ok(())
 }
 
-fn blue_green_deploy(state: &mut State) -> anyhow::Result<()> {
-    let new_slot = state.active_slot.other();
-    let old_slot = state.active_slot;
+/// Write the compose overrides for the checked out compose file and bring
+/// the shared services up, returning the services that belong in a slot
+async fn start_shared(app: &App) -> anyhow::Result<Vec<String>> {
+    let all = docker::compose_services(&app.local_path, &[&app.compose_file]).await?;
+    if let Some(missing) = app.shared.iter().find(|name| !all.contains(name)) {
+        anyhow::bail!("shared service {} is not in {}", missing, app.compose_file);
+    }
+    let services: Vec<String> = all
+        .into_iter()
+        .filter(|name| !app.shared.contains(name))
+        .collect();
+    write_overrides(app, &services)?;
 
-    let new_project = state.project_name(new_slot);
-    let old_project = state.project_name(old_slot);
+    let project = app.shared_project();
+    info!(project = %project, services = %app.shared.join(", "), "starting shared services");
+    let files = [app.compose_file.as_str(), SHARED_OVERRIDE];
+    // A newer image of a database is no reason to restart it mid-deploy
+    docker::compose_up(
+        &app.local_path,
+        &files,
+        &project,
+        &app.network,
+        &app.shared,
+        "missing",
+    )
+    .await?;
+    docker::wait_healthy(&project, app.health_timeout).await?;
 
-    info!(old = %old_project, new = %new_project, "starting blue-green deploy");
+    Ok(services)
+}
 
-    // 1. Deploy new slot
-    deploy(state, new_slot)?;
+/// Put the shared services on the app network under their service names,
+/// leave them out of the slots and create the slot `services` on the app
+/// network, so they reach the shared ones from the start
+fn write_overrides(app: &App, services: &[String]) -> anyhow::Result<()> {
+    std::fs::create_dir_all(app.local_path.join(OVERRIDE_DIR))
+        .context("failed to create compose override directory")?;
+    let network = app.network.as_str();
 
-    // 2. Wait for health
-    info!(project = %new_project, "waiting for health check");
-    if let Err(e) = docker::wait_healthy(&new_project, state.health_timeout) {
-        error!(error = %e, "health check failed, rolling back");
-        let files = [state.compose_file.as_str()];
-        let _ = docker::compose_down(&state.local_path, &files, &new_project);
+    let mut shared = serde_json::Map::new();
+    let mut slot = serde_json::Map::new();
+    for name in &app.shared {
+        shared.insert(
+            name.clone(),
+            json!({ "networks": { "default": {}, network: { "aliases": [name] } } }),
+        );
+        slot.insert(name.clone(), json!({ "scale": 0 }));
+    }
+    for name in services {
+        slot.insert(
+            name.clone(),
+            json!({ "networks": { "default": {}, network: {} } }),
+        );
+    }
+    let networks = json!({ network: { "external": true } });
+    let overrides = [
+        (
+            SHARED_OVERRIDE,
+            json!({ "services": shared, "networks": networks }),
+        ),
+        (
+            SLOT_OVERRIDE,
+            json!({ "services": slot, "networks": networks }),
+        ),
+    ];
+    for (file, content) in overrides {
+        let content =
+            serde_yaml::to_string(&content).context("failed to serialize compose override")?;
+        std::fs::write(app.local_path.join(file), content)
+            .context("failed to write compose override")?;
+    }
+    Ok(())
+}
+
+async fn blue_green_deploy(
+    report: &Report,
+    app: &App,
+    old_slot: Slot,
+    new_slot: Slot,
+    prepare: Prepare,
+    cancel: watch::Receiver<Option<Cancel>>,
+) -> anyhow::Result<()> {
+    let new_project = app.project_name(new_slot);
+    let old_project = app.project_name(old_slot);
+    let files = app.slot_files();
+
+    info!(old = %old_project, new = %new_project, "starting blue-green deploy");
+
+    // 1. Deploy new slot, 2. wait for health and 3. route traffic over,
+    // until cancelled
+    let started = async {
+        match prepare {
+            Prepare::Fresh => deploy(app, new_slot).await?,
+            Prepare::Replace => {
+                info!(project = %new_project, "removing kept previous slot");
+                docker::compose_down(&app.local_path, &files, &new_project).await?;
+                deploy(app, new_slot).await?;
+            }
+            Prepare::Reuse => {
+                info!(project = %new_project, "starting kept previous slot");
+                docker::compose_start(&app.local_path, &files, &new_project).await?;
+            }
+        }
+        let started_at = Instant::now();
+        info!(project = %new_project, "waiting for health check");
+        docker::wait_healthy(&new_project, app.health_timeout).await?;
+        if !app.probes.is_empty() {
+            info!(project = %new_project, probes = app.probes.len(), "running readiness probes");
+            let remaining = app.health_timeout.saturating_sub(started_at.elapsed());
+            probe::wait_ready(&new_project, &app.network, &app.probes, remaining).await?;
+        }
+        if let (Some(route), Some(canary)) = (&app.route, &app.canary) {
+            shift(app, route, canary, old_slot, new_slot).await?;
+        }
+        route_to(app, new_slot).await
+    };
+    if let Err(e) = cancellable(started, cancel.clone()).await {
+        if e.is::<Cancelled>() {
+            info!(project = %new_project, "tearing down cancelled slot");
+        } else {
+            error!(error = %format!("{:#}", e), "new slot failed, rolling back");
+        }
+        // All traffic back on the old slot before the new one goes away
+        if let Err(e) = route_back(app, old_slot).await {
+            warn!(error = %e, "failed to route traffic back to old slot");
+        }
+        let _ = docker::compose_down(&app.local_path, &files, &new_project).await;
         return Err(e);
     }
+    // Newer commits wait for the rollout from here on
+    report.switched(new_slot);
 
-    // 3. Stop old slot
-    info!(project = %old_project, "stopping old slot");
-    let files = [state.compose_file.as_str()];
-    if let Err(e) = docker::compose_down(&state.local_path, &files, &old_project) {
-        warn!(error = %e, "failed to stop old slot");
+    // 4. Drain and stop old slot, which can't be cancelled
+    let running = app.keep_previous == KeepPrevious::Run;
+    if !running
+        && let Some(drain) = &app.drain
+        && let Err(e) = drain_slot(drain, &old_project).await
+    {
+        warn!(error = %e, "failed to drain old slot");
+    }
+    if running {
+        info!(project = %old_project, "keeping old slot running without traffic");
+    } else if app.keep_previous == KeepPrevious::Stop || !app.bake.is_zero() {
+        // Kept to start again if the bake fails or for a rollback
+        info!(project = %old_project, "stopping old slot, keeping its containers");
+        if let Err(e) = docker::compose_stop(&app.local_path, &files, &old_project).await {
+            warn!(error = %e, "failed to stop old slot");
+        }
+    } else {
+        info!(project = %old_project, "stopping old slot");
+        if let Err(e) = docker::compose_down(&app.local_path, &files, &old_project).await {
+            warn!(error = %e, "failed to stop old slot");
+        }
     }
 
-    // 4. Update state
-    state.active_slot = new_slot;
-    state::save(
-        &state.local_path,
-        &state::PersistedState {
-            active_slot: new_slot,
-        },
-    )?;
+    // 5. Watch the new slot, which only an operator abort can cut short
+    if !app.bake.is_zero() {
+        if let Err(e) = bake(app, &new_project, cancel.clone()).await {
+            // The new slot stands, and the next deployer starts from it
+            if *cancel.borrow() == Some(Cancel::Stop) {
+                info!(project = %new_project, "deployer stopping, ending bake early");
+                if app.keep_previous == KeepPrevious::Down {
+                    remove_old(app, &old_project).await;
+                }
+                return Err(e);
+            }
+            if e.is::<Cancelled>() {
+                info!(project = %old_project, "bake aborted, bringing old slot back");
+            } else {
+                error!(error = %format!("{:#}", e), "new slot degraded, bringing old slot back");
+            }
+            if let Err(restore_error) = restore(app, old_slot, new_slot).await {
+                return Err(restore_error.context(format!("{:#}", e)));
+            }
+            report.switched(old_slot);
+            return Err(e);
+        }
+        if app.keep_previous == KeepPrevious::Down {
+            info!("bake passed");
+            remove_old(app, &old_project).await;
+        }
+    }
 
+    // 6. The caller records the deploy once this returns
     info!(active = %new_project, "deploy complete");
 
     Ok(())
 }
+
+/// Remove the old slot once it is no longer kept for the bake
+async fn remove_old(app: &App, project: &str) {
+    info!(project = %project, "removing old slot");
+    if let Err(e) = docker::compose_down(&app.local_path, &app.slot_files(), project).await {
+        warn!(error = %e, "failed to remove old slot");
+    }
+}
+
+/// Give a slot without traffic the drain timeout to finish its in-flight
+/// requests, then stop its containers with the drain signal. Traefik v3
+/// doesn't report open connections per service, so the whole timeout is waited.
+async fn drain_slot(drain: &Drain, project: &str) -> anyhow::Result<()> {
+    info!(project = %project, secs = drain.timeout, "draining old slot");
+    tokio::time::sleep(Duration::from_secs(drain.timeout)).await;
+    info!(project = %project, signal = %drain.stop_signal, "signalling old slot to shut down");
+    docker::stop_with_signal(
+        project,
+        &drain.stop_signal,
+        Duration::from_secs(drain.stop_grace),
+    )
+    .await
+}
+
+/// Watch the new slot for `app.bake` after the switch, until cancelled;
+/// a degraded slot fails the bake with context for the history
+async fn bake(
+    app: &App,
+    project: &str,
+    cancel: watch::Receiver<Option<Cancel>>,
+) -> anyhow::Result<()> {
+    info!(project = %project, secs = app.bake.as_secs(), "baking new slot");
+    cancellable(watch_slot(app, project, app.bake), cancel)
+        .await
+        .map_err(|e| {
+            if e.is::<Cancelled>() {
+                e
+            } else {
+                e.context("new slot degraded during bake")
+            }
+        })
+}
+
+/// Move traffic to the new slot through the canary steps, watching it at each
+async fn shift(
+    app: &App,
+    route: &Route,
+    canary: &Canary,
+    old_slot: Slot,
+    new_slot: Slot,
+) -> anyhow::Result<()> {
+    let new_project = app.project_name(new_slot);
+    // A broken release being fixed leaves nothing to split traffic with
+    let Some(old) = live_backend(app, route, old_slot).await? else {
+        info!(project = %app.project_name(old_slot), "old slot has no running container, skipping canary steps");
+        return Ok(());
+    };
+    let new = backend(app, route, new_slot).await?;
+    let step_duration = Duration::from_secs(canary.step_duration);
+    for &weight in &canary.steps {
+        info!(project = %new_project, weight, "shifting traffic to new slot");
+        set_routes(app, &[(&old, 100 - weight), (&new, weight)]).await?;
+        watch_slot(app, &new_project, step_duration)
+            .await
+            .with_context(|| format!("new slot degraded at {}% of traffic", weight))?;
+    }
+    Ok(())
+}
+
+/// Send all traffic to `slot`, if rollploy owns routing
+async fn route_to(app: &App, slot: Slot) -> anyhow::Result<()> {
+    let Some(route) = &app.route else {
+        return Ok(());
+    };
+    let backend = backend(app, route, slot).await?;
+    info!(project = %backend.project, "routing traffic");
+    set_routes(app, &[(&backend, 100)]).await
+}
+
+/// Send all traffic back to `slot` after a failed rollout, unless nothing
+/// runs there to take it
+async fn route_back(app: &App, slot: Slot) -> anyhow::Result<()> {
+    let Some(route) = &app.route else {
+        return Ok(());
+    };
+    let Some(backend) = live_backend(app, route, slot).await? else {
+        info!(project = %app.project_name(slot), "old slot has no running container, leaving routes as they are");
+        return Ok(());
+    };
+    info!(project = %backend.project, "routing traffic");
+    set_routes(app, &[(&backend, 100)]).await
+}
+
+/// Rewrite the routes and wait until Traefik serves them
+async fn set_routes(app: &App, backends: &[(&traefik::Backend, u8)]) -> anyhow::Result<()> {
+    traefik::write_routes(&app.proxy, &app.name, backends)?;
+    traefik::verify_routes(&app.proxy, &app.name, &app.network, backends).await
+}
+
+async fn backend(app: &App, route: &Route, slot: Slot) -> anyhow::Result<traefik::Backend> {
+    match live_backend(app, route, slot).await? {
+        Some(backend) => Ok(backend),
+        None => anyhow::bail!(
+            "no running container for service {} in {}",
+            route.service,
+            app.project_name(slot)
+        ),
+    }
+}
+
+/// The routed service of `slot`, or None if none of its containers run
+async fn live_backend(
+    app: &App,
+    route: &Route,
+    slot: Slot,
+) -> anyhow::Result<Option<traefik::Backend>> {
+    let project = app.project_name(slot);
+    let containers = docker::service_container_names(&project, &route.service).await?;
+    if containers.is_empty() {
+        return Ok(None);
+    }
+    Ok(Some(traefik::Backend::new(
+        &project,
+        &containers,
+        route.port,
+    )))
+}
+
+/// Watch a live slot for `duration`, failing on crashed, restarted or
+/// unhealthy containers and on probes that fail repeatedly
+async fn watch_slot(app: &App, project: &str, duration: Duration) -> anyhow::Result<()> {
+    let baseline = docker::restart_counts(project).await?;
+    let deadline = Instant::now() + duration;
+    let mut failures = vec![0; app.probes.len()];
+    loop {
+        docker::check_stable(project, &baseline).await?;
+        for (probe, failed) in app.probes.iter().zip(&mut failures) {
+            match probe::attempt(project, &app.network, probe).await {
+                Ok(()) => *failed = 0,
+                Err(e) => {
+                    *failed += 1;
+                    warn!(probe = %probe, error = %e, failures = *failed, "probe failed on live slot");
+                    if *failed >= probe.failure_threshold {
+                        anyhow::bail!("probe {} failed {} times in a row: {:#}", probe, failed, e);
+                    }
+                }
+            }
+        }
+
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            return Ok(());
+        }
+        tokio::time::sleep(remaining.min(WATCH_INTERVAL)).await;
+    }
+}
+
+/// Start the kept old slot again and remove the new one. If the old slot
+/// doesn't come back healthy, the new slot is left serving.
+async fn restore(app: &App, old_slot: Slot, new_slot: Slot) -> anyhow::Result<()> {
+    let (old_project, new_project) = (app.project_name(old_slot), app.project_name(new_slot));
+    let files = app.slot_files();
+    let started = async {
+        docker::compose_start(&app.local_path, &files, &old_project).await?;
+        docker::wait_healthy(&old_project, app.health_timeout).await?;
+        route_to(app, old_slot).await
+    };
+    if let Err(e) = started.await {
+        return Err(e.context(format!(
+            "failed to bring {} back, keeping {}",
+            old_project, new_project
+        )));
+    }
+    info!(project = %new_project, "removing new slot");
+    if let Err(e) = docker::compose_down(&app.local_path, &files, &new_project).await {
+        warn!(error = %e, "failed to remove new slot");
+    }
+    Ok(())
+}
@@ -1,3 +1,5 @@
-mod deployer;
+pub mod deployer;
+pub mod supervisor;
 
 pub use deployer::{Deployer, DeployerArgs};
+pub use supervisor::{RestartPolicy, Source, Supervisor, SupervisorArgs};
@@ -0,0 +1,491 @@
+use super::{deployer, Deployer, DeployerArgs};
+use crate::config::{self, Config, CronTarget, DeployTarget};
+use crate::cron::{self, CronRunner, CronRunnerArgs};
+use crate::git::Track;
+use crate::traefik::{self, Entry};
+use ractor::{
+    async_trait, Actor, ActorCell, ActorProcessingErr, ActorRef, RpcReplyPort, SupervisionEvent,
+};
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::time::{Duration, Instant, SystemTime};
+use tokio::sync::oneshot;
+use tracing::{error, info, warn};
+
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(300);
+
+/// Root actor: owns one child per target and restarts them when they crash
+pub struct Supervisor;
+
+pub struct SupervisorArgs {
+    pub source: Source,
+    pub restart: RestartPolicy,
+    /// Gets the reason if the supervisor stops because its targets failed
+    pub failed: oneshot::Sender<String>,
+}
+
+/// Where the supervisor gets its targets from
+pub enum Source {
+    /// Fixed targets given on the command line
+    Static(Config),
+    /// A config file that is re-read when it changes
+    File {
+        path: PathBuf,
+        reload_interval: Duration,
+    },
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct RestartPolicy {
+    /// Restarts allowed within `window` before giving up on a child
+    pub max_restarts: usize,
+    pub window: Duration,
+}
+
+pub struct State {
+    config_path: Option<PathBuf>,
+    config_mtime: Option<SystemTime>,
+    restart: RestartPolicy,
+    children: HashMap<String, Child>,
+    failed: Option<oneshot::Sender<String>>,
+}
+
+struct Child {
+    target: Target,
+    /// None while waiting for a restart or after giving up
+    cell: Option<ActorCell>,
+    restarts: Vec<Instant>,
+    gave_up: bool,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+#[allow(clippy::large_enum_variant)]
+enum Target {
+    Deploy(DeployTarget),
+    Cron(CronTarget),
+}
+
+#[derive(Debug)]
+pub enum Message {
+    Reload,
+    Restart(String),
+    /// A webhook reported new commits; poll every matching child now and
+    /// reply with the names of the children that were poked
+    Push(Push, RpcReplyPort<Vec<String>>),
+}
+
+/// Repository and ref from a push webhook
+#[derive(Debug, Clone)]
+pub struct Push {
+    /// Every URL the payload lists for the repository (https, ssh, web)
+    pub repos: Vec<String>,
+    /// Full ref name, e.g. `refs/heads/main` or `refs/tags/v1.2.0`
+    pub git_ref: String,
+}
+
+impl Target {
+    fn actor_name(&self) -> String {
+        match self {
+            Target::Deploy(t) => format!("deployer-{}", t.name()),
+            Target::Cron(t) => format!("cron-{}", t.name()),
+        }
+    }
+
+    /// Whether a push to `git_ref` of one of `repos` can move this target
+    fn matches(&self, push: &Push) -> bool {
+        let (repo, track) = match self {
+            Target::Deploy(t) => (&t.repo, t.track()),
+            Target::Cron(t) => (&t.repo, Track::Branch(t.branch.clone())),
+        };
+        let repo = normalize_url(repo);
+        if !push.repos.iter().any(|r| normalize_url(r) == repo) {
+            return false;
+        }
+        match track {
+            Track::Branch(branch) => push.git_ref == format!("refs/heads/{}", branch),
+            Track::Tag(_) => push.git_ref.starts_with("refs/tags/"),
+            // A pinned commit only moves through a config change
+            Track::Commit(_) => false,
+        }
+    }
+}
+
+#[async_trait]
+impl Actor for Supervisor {
+    type Msg = Message;
+    type State = State;
+    type Arguments = SupervisorArgs;
+
+    async fn pre_start(
+        &self,
+        myself: ActorRef<Self::Msg>,
+        args: Self::Arguments,
+    ) -> Result<Self::State, ActorProcessingErr> {
+        let mut state = State {
+            config_path: None,
+            config_mtime: None,
+            restart: args.restart,
+            children: HashMap::new(),
+            failed: Some(args.failed),
+        };
+
+        let config = match args.source {
+            Source::Static(config) => config,
+            Source::File {
+                path,
+                reload_interval,
+            } => {
+                info!(config = %path.display(), "starting supervisor");
+                // Fail startup on a broken config; later reloads only log
+                state.config_mtime = modified(&path);
+                let config = config::load(&path)?;
+                state.config_path = Some(path);
+                myself.send_interval(reload_interval, || Message::Reload);
+                config
+            }
+        };
+
+        reconcile(&myself, &mut state, config).await;
+
+        Ok(state)
+    }
+
+    async fn handle(
+        &self,
+        myself: ActorRef<Self::Msg>,
+        message: Self::Msg,
+        state: &mut Self::State,
+    ) -> Result<(), ActorProcessingErr> {
+        match message {
+            Message::Reload => {
+                let Some(path) = state.config_path.clone() else {
+                    return Ok(());
+                };
+                let mtime = modified(&path);
+                if mtime == state.config_mtime {
+                    return Ok(());
+                }
+                state.config_mtime = mtime;
+
+                info!(config = %path.display(), "config changed, reloading");
+                match config::load(&path) {
+                    Ok(config) => reconcile(&myself, state, config).await,
+                    Err(e) => {
+                        error!(error = %e, "failed to reload config, keeping current targets")
+                    }
+                }
+            }
+            Message::Restart(name) => {
+                let Some(child) = state.children.get_mut(&name) else {
+                    return Ok(());
+                };
+                // Already replaced by a config reload
+                if child.cell.is_some() || child.gave_up {
+                    return Ok(());
+                }
+                info!(actor = %name, "restarting");
+                match spawn(&myself, &name, &child.target).await {
+                    Ok(cell) => child.cell = Some(cell),
+                    Err(e) => {
+                        schedule_restart(&myself, state, &name, &format!("start failed: {}", e))
+                    }
+                }
+            }
+            Message::Push(push, reply) => {
+                let mut poked = Vec::new();
+                for (name, child) in &state.children {
+                    let Some(cell) = &child.cell else {
+                        continue;
+                    };
+                    if !child.target.matches(&push) {
+                        continue;
+                    }
+                    let sent = match child.target {
+                        Target::Deploy(_) => cell.send_message(deployer::Message::Tick).is_ok(),
+                        Target::Cron(_) => cell.send_message(cron::Message::GitPull).is_ok(),
+                    };
+                    if sent {
+                        poked.push(name.clone());
+                    }
+                }
+                info!(git_ref = %push.git_ref, targets = ?poked, "push received");
+                let _ = reply.send(poked);
+            }
+        }
+        Ok(())
+    }
+
+    async fn handle_supervisor_evt(
+        &self,
+        myself: ActorRef<Self::Msg>,
+        message: SupervisionEvent,
+        state: &mut Self::State,
+    ) -> Result<(), ActorProcessingErr> {
+        let (cell, reason) = match message {
+            SupervisionEvent::ActorTerminated(cell, _, reason) => (
+                cell,
+                format!("stopped: {}", reason.as_deref().unwrap_or("no reason")),
+            ),
+            SupervisionEvent::ActorFailed(cell, e) => (cell, format!("failed: {}", e)),
+            _ => return Ok(()),
+        };
+
+        let Some(name) = detach(state, &cell) else {
+            return Ok(());
+        };
+        schedule_restart(&myself, state, &name, &reason);
+
+        Ok(())
+    }
+}
+
+/// Bring running children in line with `config`, leaving unchanged entries alone
+async fn reconcile(myself: &ActorRef<Message>, state: &mut State, config: Config) {
+    // Deployers on the edge attach to it as they start
+    let edge_apps: Vec<_> = config
+        .deploys
+        .iter()
+        .filter(|d| matches!(d.entry(), Entry::Edge { .. }))
+        .map(|d| (d.name(), d.tls.clone()))
+        .collect();
+    if !edge_apps.is_empty()
+        && let Err(e) = traefik::reconcile_edge(&config.edge.image, &edge_apps).await
+    {
+        error!(error = %e, "failed to set up the edge proxy");
+    }
+
+    let mut desired: HashMap<String, Target> = HashMap::new();
+    for target in config
+        .deploys
+        .into_iter()
+        .map(Target::Deploy)
+        .chain(config.crons.into_iter().map(Target::Cron))
+    {
+        desired.insert(target.actor_name(), target);
+    }
+
+    // Stop removed or changed children
+    let stale: Vec<String> = state
+        .children
+        .iter()
+        .filter(|(name, child)| desired.get(*name) != Some(&child.target))
+        .map(|(name, _)| name.clone())
+        .collect();
+    for name in stale {
+        let Some(child) = state.children.remove(&name) else {
+            continue;
+        };
+        let Some(cell) = child.cell else {
+            continue;
+        };
+        info!(actor = %name, "stopping");
+        if let Err(e) = cell
+            .stop_and_wait(Some("config changed".to_string()), None)
+            .await
+        {
+            warn!(actor = %name, error = %e, "failed to stop");
+        }
+    }
+
+    // Only once stopped deployers can no longer write their routes back. The
+    // edge belongs to `serve`, so single-target runs leave it alone.
+    if state.config_path.is_some() {
+        let edge_names: Vec<String> = edge_apps.into_iter().map(|(name, _)| name).collect();
+        if let Err(e) = traefik::prune_edge(&edge_names) {
+            error!(error = %e, "failed to remove stale edge routes");
+        }
+    }
+
+    // Start new or changed children
+    for (name, target) in desired {
+        if state.children.contains_key(&name) {
+            continue;
+        }
+        info!(actor = %name, "starting");
+        let cell = spawn(myself, &name, &target).await;
+        state.children.insert(
+            name.clone(),
+            Child {
+                target,
+                cell: None,
+                restarts: Vec::new(),
+                gave_up: false,
+            },
+        );
+        match cell {
+            Ok(cell) => {
+                if let Some(child) = state.children.get_mut(&name) {
+                    child.cell = Some(cell);
+                }
+            }
+            Err(e) => schedule_restart(myself, state, &name, &format!("start failed: {}", e)),
+        }
+    }
+}
+
+/// Queue a restart with exponential backoff, or give up once the budget is spent
+fn schedule_restart(myself: &ActorRef<Message>, state: &mut State, name: &str, reason: &str) {
+    let policy = state.restart;
+    let Some(child) = state.children.get_mut(name) else {
+        return;
+    };
+
+    let now = Instant::now();
+    child
+        .restarts
+        .retain(|at| now.duration_since(*at) < policy.window);
+
+    if child.restarts.len() >= policy.max_restarts {
+        error!(
+            actor = %name,
+            reason = %reason,
+            restarts = child.restarts.len(),
+            window_secs = policy.window.as_secs(),
+            "restart budget exhausted, giving up"
+        );
+        child.gave_up = true;
+
+        // Nothing left to supervise and no config to fix it with
+        if state.config_path.is_none() && state.children.values().all(|c| c.gave_up) {
+            let reason = "all children gave up".to_string();
+            if let Some(failed) = state.failed.take() {
+                let _ = failed.send(reason.clone());
+            }
+            myself.stop(Some(reason));
+        }
+        return;
+    }
+
+    let attempt = child.restarts.len() as u32;
+    let delay = INITIAL_BACKOFF
+        .saturating_mul(2u32.saturating_pow(attempt))
+        .min(MAX_BACKOFF);
+    child.restarts.push(now);
+
+    warn!(
+        actor = %name,
+        reason = %reason,
+        attempt = attempt + 1,
+        delay_secs = delay.as_secs(),
+        "scheduling restart"
+    );
+
+    let name = name.to_string();
+    myself.send_after(delay, move || Message::Restart(name));
+}
+
+async fn spawn(
+    myself: &ActorRef<Message>,
+    name: &str,
+    target: &Target,
+) -> Result<ActorCell, ActorProcessingErr> {
+    let supervisor = myself.get_cell();
+    let cell = match target {
+        Target::Deploy(t) => {
+            let args = DeployerArgs {
+                app_name: t.name(),
+                repo_url: t.repo.clone(),
+                git: t.git(),
+                local_path: t.local_path(),
+                include: t.include.clone(),
+                exclude: t.exclude.clone(),
+                compose_file: t.compose.clone(),
+                shared: t.shared.clone(),
+                entry: t.entry(),
+                traefik_image: t.traefik_image.clone(),
+                interval: Duration::from_secs(t.interval),
+                health_timeout: Duration::from_secs(t.health_timeout),
+                probes: t.probes.clone(),
+                bake: Duration::from_secs(t.bake),
+                route: t.route.clone(),
+                canary: t.canary.clone(),
+                tls: t.tls.clone(),
+                drain: t.drain.clone(),
+                keep_previous: t.keep_previous,
+                retention: Duration::from_secs(t.retention),
+                history_limit: t.history_limit,
+            };
+            let (actor, _) =
+                Actor::spawn_linked(Some(name.to_string()), Deployer, args, supervisor).await?;
+            actor.get_cell()
+        }
+        Target::Cron(t) => {
+            let args = CronRunnerArgs {
+                repo_url: t.repo.clone(),
+                git: t.git(),
+                local_path: t.local_path(),
+                check_interval: Duration::from_secs(t.interval),
+            };
+            let (actor, _) =
+                Actor::spawn_linked(Some(name.to_string()), CronRunner, args, supervisor).await?;
+            actor.get_cell()
+        }
+    };
+    Ok(cell)
+}
+
+/// Clear the running cell of the child owning `cell`, returning its name
+fn detach(state: &mut State, cell: &ActorCell) -> Option<String> {
+    let (name, child) = state.children.iter_mut().find(|(_, child)| {
+        child
+            .cell
+            .as_ref()
+            .is_some_and(|c| c.get_id() == cell.get_id())
+    })?;
+    child.cell = None;
+    Some(name.clone())
+}
+
+/// Reduce the many spellings of a repository URL to `host/owner/repo`
+fn normalize_url(url: &str) -> String {
+    let url = url.trim().to_lowercase();
+    let rest = url.split_once("://").map_or(url.as_str(), |(_, rest)| rest);
+    let rest = rest.split_once('@').map_or(rest, |(_, rest)| rest);
+    // scp-like `host:owner/repo` or `host:port/owner/repo`
+    let (host, path) = match rest.find([':', '/']) {
+        Some(i) => (&rest[..i], &rest[i + 1..]),
+        None => (rest, ""),
+    };
+    let path = match path.split_once('/') {
+        Some((port, path)) if port.chars().all(|c| c.is_ascii_digit()) => path,
+        _ => path,
+    };
+    let path = path.trim_end_matches('/').trim_end_matches(".git");
+    format!("{}/{}", host, path)
+}
+
+fn modified(path: &std::path::Path) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn normalize_url_spellings() {
+        for url in [
+            "https://github.com/User/App",
+            "https://github.com/user/app.git",
+            "https://token@github.com/user/app/",
+            "git@github.com:user/app.git",
+            "ssh://git@github.com/user/app.git",
+            "ssh://git@github.com:22/user/app.git",
+        ] {
+            assert_eq!(normalize_url(url), "github.com/user/app", "{}", url);
+        }
+    }
+
+    #[test]
+    fn normalize_url_keeps_repos_apart() {
+        assert_ne!(
+            normalize_url("git@github.com:user/app.git"),
+            normalize_url("git@gitlab.com:user/app.git")
+        );
+        assert_ne!(
+            normalize_url("https://github.com/user/app"),
+            normalize_url("https://github.com/user/app2")
+        );
+    }
+}
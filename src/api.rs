@@ -0,0 +1,157 @@
+use crate::actors::deployer::{Message, Status};
+use anyhow::{bail, Context, Result};
+use axum::extract::Path;
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use axum::routing::{get, post};
+use axum::{Json, Router};
+use ractor::rpc::CallResult;
+use ractor::ActorRef;
+use std::net::SocketAddr;
+use std::os::unix::fs::FileTypeExt;
+use std::path::PathBuf;
+use std::time::Duration;
+use tracing::{info, warn};
+
+const DEPLOYER_PREFIX: &str = "deployer-";
+const CALL_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Where the control API listens
+pub enum Listen {
+    Tcp(SocketAddr),
+    Unix(PathBuf),
+}
+
+/// Bind the listener and serve the API in the background
+pub async fn start(listen: Listen) -> Result<()> {
+    let app = router();
+
+    match listen {
+        Listen::Tcp(addr) => {
+            if !addr.ip().is_loopback() {
+                warn!(addr = %addr, "control API is not bound to localhost and has no authentication");
+            }
+            let listener = tokio::net::TcpListener::bind(addr)
+                .await
+                .with_context(|| format!("failed to bind {}", addr))?;
+            info!(addr = %addr, "control API listening");
+            tokio::spawn(async move {
+                if let Err(e) = axum::serve(listener, app).await {
+                    warn!(error = %e, "control API stopped");
+                }
+            });
+        }
+        Listen::Unix(path) => {
+            // Remove a socket left behind by a previous run, but nothing else
+            if let Ok(metadata) = std::fs::symlink_metadata(&path) {
+                if !metadata.file_type().is_socket() {
+                    bail!("{} exists and is not a socket", path.display());
+                }
+                std::fs::remove_file(&path)
+                    .with_context(|| format!("failed to remove {}", path.display()))?;
+            }
+            let listener = tokio::net::UnixListener::bind(&path)
+                .with_context(|| format!("failed to bind {}", path.display()))?;
+            info!(socket = %path.display(), "control API listening");
+            tokio::spawn(async move {
+                if let Err(e) = axum::serve(listener, app).await {
+                    warn!(error = %e, "control API stopped");
+                }
+            });
+        }
+    }
+
+    Ok(())
+}
+
+fn router() -> Router {
+    Router::new()
+        .route("/apps", get(list_apps))
+        .route("/apps/{app}", get(get_app))
+        .route("/apps/{app}/deploy", post(deploy_now))
+        .route("/apps/{app}/pause", post(pause))
+        .route("/apps/{app}/resume", post(resume))
+        .route("/apps/{app}/rollback", post(rollback))
+        .route("/apps/{app}/abort", post(abort))
+}
+
+enum ApiError {
+    NotFound(String),
+    Unavailable(String),
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        let (code, error) = match self {
+            ApiError::NotFound(app) => (StatusCode::NOT_FOUND, format!("unknown app {}", app)),
+            ApiError::Unavailable(reason) => (StatusCode::SERVICE_UNAVAILABLE, reason),
+        };
+        (code, Json(serde_json::json!({ "error": error }))).into_response()
+    }
+}
+
+async fn list_apps() -> Json<Vec<Status>> {
+    let mut apps = Vec::new();
+    for name in ractor::registry::registered() {
+        let Some(app) = name.strip_prefix(DEPLOYER_PREFIX) else {
+            continue;
+        };
+        match status(app).await {
+            Ok(status) => apps.push(status),
+            Err(ApiError::Unavailable(reason)) => {
+                warn!(app = %app, reason = %reason, "skipping app")
+            }
+            Err(ApiError::NotFound(_)) => {}
+        }
+    }
+    apps.sort_by(|a, b| a.app.cmp(&b.app));
+    Json(apps)
+}
+
+async fn get_app(Path(app): Path<String>) -> Result<Json<Status>, ApiError> {
+    status(&app).await.map(Json)
+}
+
+async fn deploy_now(Path(app): Path<String>) -> Result<StatusCode, ApiError> {
+    send(&app, Message::DeployNow)
+}
+
+async fn pause(Path(app): Path<String>) -> Result<StatusCode, ApiError> {
+    send(&app, Message::Pause)
+}
+
+async fn resume(Path(app): Path<String>) -> Result<StatusCode, ApiError> {
+    send(&app, Message::Resume)
+}
+
+async fn rollback(Path(app): Path<String>) -> Result<StatusCode, ApiError> {
+    send(&app, Message::Rollback)
+}
+
+async fn abort(Path(app): Path<String>) -> Result<StatusCode, ApiError> {
+    send(&app, Message::Abort)
+}
+
+fn deployer(app: &str) -> Result<ActorRef<Message>, ApiError> {
+    ActorRef::where_is(format!("{}{}", DEPLOYER_PREFIX, app))
+        .ok_or_else(|| ApiError::NotFound(app.to_string()))
+}
+
+async fn status(app: &str) -> Result<Status, ApiError> {
+    let actor = deployer(app)?;
+    match actor.call(Message::Status, Some(CALL_TIMEOUT)).await {
+        Ok(CallResult::Success(status)) => Ok(status),
+        Ok(CallResult::Timeout) => Err(ApiError::Unavailable(
+            "deployer is busy, try again later".to_string(),
+        )),
+        Ok(CallResult::SenderError) | Err(_) => Err(ApiError::NotFound(app.to_string())),
+    }
+}
+
+/// Queue an action; it runs after whatever the deployer is currently doing
+fn send(app: &str, message: Message) -> Result<StatusCode, ApiError> {
+    deployer(app)?
+        .send_message(message)
+        .map_err(|_| ApiError::NotFound(app.to_string()))?;
+    Ok(StatusCode::ACCEPTED)
+}
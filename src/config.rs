@@ -0,0 +1,547 @@
+use crate::git::{self, Auth, DivergePolicy, Track};
+use crate::paths::PathFilter;
+use crate::probe::{Check, Probe};
+use crate::traefik::Entry;
+use anyhow::{bail, Context, Result};
+use serde::Deserialize;
+use std::path::{Path, PathBuf};
+
+/// Targets managed by `rollploy serve`
+#[derive(Debug, Deserialize, Default)]
+pub struct Config {
+    #[serde(default)]
+    pub deploys: Vec<DeployTarget>,
+    #[serde(default)]
+    pub crons: Vec<CronTarget>,
+    /// The shared proxy of deploys routed by host or path prefix
+    #[serde(default)]
+    pub edge: Edge,
+}
+
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+pub struct Edge {
+    /// Image of the edge proxy's Traefik container
+    #[serde(default = "default_traefik_image")]
+    pub image: String,
+}
+
+impl Default for Edge {
+    fn default() -> Self {
+        Self {
+            image: default_traefik_image(),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+pub struct DeployTarget {
+    /// Defaults to the repository name
+    pub name: Option<String>,
+    pub repo: String,
+    #[serde(default = "default_branch")]
+    pub branch: String,
+    /// Deploy the highest version tag matching this glob instead of the branch tip
+    pub tag: Option<String>,
+    /// Deploy exactly this commit instead of the branch tip
+    pub commit: Option<String>,
+    /// What to do when the branch was force-pushed or the worktree is dirty
+    #[serde(default)]
+    pub on_diverge: DivergePolicy,
+    /// Credentials for private repositories
+    #[serde(default)]
+    pub auth: Auth,
+    /// Init and update git submodules
+    #[serde(default)]
+    pub submodules: bool,
+    /// Check out only this directory of the repository
+    pub subdir: Option<String>,
+    /// Only roll out when a changed file matches one of these globs
+    #[serde(default)]
+    pub include: Vec<String>,
+    /// Ignore changed files matching these globs
+    #[serde(default)]
+    pub exclude: Vec<String>,
+    #[serde(default = "default_compose")]
+    pub compose: String,
+    /// Compose services, such as databases, run once for both slots
+    #[serde(default)]
+    pub shared: Vec<String>,
+    /// Host port of the app's own Traefik container
+    pub port: Option<u16>,
+    /// Serve the app through the shared edge proxy for requests to this host
+    pub host: Option<String>,
+    /// Serve the app through the shared edge proxy for paths under this prefix
+    pub path_prefix: Option<String>,
+    /// Image of the app's Traefik container
+    #[serde(default = "default_traefik_image")]
+    pub traefik_image: String,
+    #[serde(default = "default_interval")]
+    pub interval: u64,
+    #[serde(default = "default_health_timeout")]
+    pub health_timeout: u64,
+    /// Readiness checks the new slot must pass before the switch
+    #[serde(default)]
+    pub probes: Vec<Probe>,
+    /// Seconds to watch the new slot after the switch, rolling back if it
+    /// degrades; 0 removes the old slot right away
+    #[serde(default)]
+    pub bake: u64,
+    /// Service Traefik sends traffic to; rollploy then routes to the active
+    /// slot itself instead of relying on compose labels
+    pub route: Option<Route>,
+    /// Shift traffic to the new slot in steps instead of all at once
+    pub canary: Option<Canary>,
+    /// Serve the app over HTTPS
+    pub tls: Option<Tls>,
+    /// Let the old slot finish its requests before stopping it
+    pub drain: Option<Drain>,
+    /// What happens to the old slot after a successful switch
+    #[serde(default)]
+    pub keep_previous: KeepPrevious,
+    /// Seconds a kept old slot stays around for a fast rollback
+    #[serde(default = "default_retention")]
+    pub retention: u64,
+    /// Deploy history entries to keep
+    #[serde(default = "default_history_limit")]
+    pub history_limit: usize,
+    pub dir: Option<PathBuf>,
+}
+
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+pub struct CronTarget {
+    /// Defaults to the repository name
+    pub name: Option<String>,
+    pub repo: String,
+    #[serde(default = "default_branch")]
+    pub branch: String,
+    /// What to do when the branch was force-pushed or the worktree is dirty
+    #[serde(default)]
+    pub on_diverge: DivergePolicy,
+    /// Credentials for private repositories
+    #[serde(default)]
+    pub auth: Auth,
+    /// Init and update git submodules
+    #[serde(default)]
+    pub submodules: bool,
+    /// Check out only this directory of the repository
+    pub subdir: Option<String>,
+    #[serde(default = "default_interval")]
+    pub interval: u64,
+    pub dir: Option<PathBuf>,
+}
+
+/// The compose service that receives the app's traffic
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+pub struct Route {
+    pub service: String,
+    /// Port the service listens on inside its containers
+    pub port: u16,
+}
+
+/// Weighted traffic shifting between the old and new slot
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+pub struct Canary {
+    /// Percent of traffic sent to the new slot at each step
+    #[serde(default = "default_canary_steps")]
+    pub steps: Vec<u8>,
+    /// Seconds to watch the new slot at each step
+    #[serde(default = "default_step_duration")]
+    pub step_duration: u64,
+}
+
+/// What happens to the old slot once traffic left it
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum KeepPrevious {
+    /// Remove its containers
+    #[default]
+    Down,
+    /// Stop its containers, keeping them to start again on rollback
+    Stop,
+    /// Leave it running without traffic
+    Run,
+}
+
+/// How the old slot is stopped once traffic left it
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+pub struct Drain {
+    /// Seconds the old slot gets to finish in-flight requests
+    #[serde(default = "default_drain_timeout")]
+    pub timeout: u64,
+    /// Signal asking the old slot's containers to shut down
+    #[serde(default = "default_stop_signal")]
+    pub stop_signal: String,
+    /// Seconds between the stop signal and SIGKILL
+    #[serde(default = "default_stop_grace")]
+    pub stop_grace: u64,
+}
+
+/// HTTPS with a certificate from ACME or from files
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+pub struct Tls {
+    /// Names the certificate covers; on the edge proxy the host is used if empty
+    #[serde(default)]
+    pub domains: Vec<String>,
+    pub acme: Option<Acme>,
+    /// PEM certificate chain to serve instead of one from ACME
+    pub cert: Option<PathBuf>,
+    /// PEM private key of `cert`
+    pub key: Option<PathBuf>,
+    /// Redirect plain HTTP requests to HTTPS
+    #[serde(default = "default_redirect")]
+    pub redirect: bool,
+    /// Host port of HTTPS on the app's own Traefik
+    #[serde(default = "default_https_port")]
+    pub port: u16,
+}
+
+/// Certificates issued through Traefik's ACME client
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+pub struct Acme {
+    /// Account contact
+    pub email: Option<String>,
+    #[serde(default)]
+    pub challenge: Challenge,
+    /// Directory URL of the CA; defaults to Let's Encrypt
+    pub ca_server: Option<String>,
+    /// PEM bundle to trust for `ca_server`, e.g. Pebble's
+    pub ca_cert: Option<PathBuf>,
+}
+
+/// How the CA verifies control of the domains
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum Challenge {
+    /// HTTP-01 on port 80
+    #[default]
+    Http,
+    /// TLS-ALPN-01 on port 443
+    TlsAlpn,
+}
+
+pub fn default_canary_steps() -> Vec<u8> {
+    vec![5, 25, 50, 100]
+}
+
+fn default_step_duration() -> u64 {
+    60
+}
+
+pub fn default_retention() -> u64 {
+    3600
+}
+
+fn default_drain_timeout() -> u64 {
+    30
+}
+
+pub fn default_stop_signal() -> String {
+    "SIGTERM".to_string()
+}
+
+pub fn default_stop_grace() -> u64 {
+    10
+}
+
+fn default_redirect() -> bool {
+    true
+}
+
+pub fn default_https_port() -> u16 {
+    443
+}
+
+fn default_branch() -> String {
+    "main".to_string()
+}
+
+fn default_compose() -> String {
+    "docker-compose.yml".to_string()
+}
+
+pub fn default_traefik_image() -> String {
+    "traefik:v3.0".to_string()
+}
+
+fn default_interval() -> u64 {
+    60
+}
+
+fn default_health_timeout() -> u64 {
+    120
+}
+
+fn default_history_limit() -> usize {
+    100
+}
+
+impl DeployTarget {
+    pub fn name(&self) -> String {
+        self.name.clone().unwrap_or_else(|| repo_name(&self.repo))
+    }
+
+    pub fn track(&self) -> Track {
+        match (&self.tag, &self.commit) {
+            (Some(pattern), _) => Track::Tag(pattern.clone()),
+            (None, Some(sha)) => Track::Commit(sha.clone()),
+            (None, None) => Track::Branch(self.branch.clone()),
+        }
+    }
+
+    pub fn local_path(&self) -> PathBuf {
+        self.dir
+            .clone()
+            .unwrap_or_else(|| derive_local_path(&self.name()))
+    }
+
+    pub fn entry(&self) -> Entry {
+        match self.port {
+            Some(port) => Entry::Port(port),
+            None => Entry::Edge {
+                host: self.host.clone(),
+                path_prefix: self.path_prefix.clone(),
+            },
+        }
+    }
+
+    pub fn git(&self) -> git::Options {
+        git::Options {
+            track: self.track(),
+            on_diverge: self.on_diverge,
+            auth: self.auth.clone(),
+            submodules: self.submodules,
+            subdir: self.subdir.clone(),
+            extra_paths: Vec::new(),
+        }
+    }
+}
+
+impl CronTarget {
+    pub fn name(&self) -> String {
+        self.name.clone().unwrap_or_else(|| repo_name(&self.repo))
+    }
+
+    pub fn local_path(&self) -> PathBuf {
+        self.dir
+            .clone()
+            .unwrap_or_else(|| derive_local_path(&self.name()))
+    }
+
+    pub fn git(&self) -> git::Options {
+        git::Options {
+            track: Track::Branch(self.branch.clone()),
+            on_diverge: self.on_diverge,
+            auth: self.auth.clone(),
+            submodules: self.submodules,
+            subdir: self.subdir.clone(),
+            extra_paths: Vec::new(),
+        }
+    }
+}
+
+pub fn load(path: &Path) -> Result<Config> {
+    let content = std::fs::read_to_string(path)
+        .with_context(|| format!("failed to read {}", path.display()))?;
+    let mut config: Config = serde_yaml::from_str(&content).context("failed to parse config")?;
+    separate_cron_dirs(&mut config);
+    validate(&config)?;
+    Ok(config)
+}
+
+/// Reject configs that would only fail once their actors start
+/// Move crons whose default checkout is a deploy's to `cron-<name>`, e.g.
+/// a deploy and a cron of the same repo; other crons keep the plain default
+fn separate_cron_dirs(config: &mut Config) {
+    let deploy_dirs: std::collections::HashSet<PathBuf> = config
+        .deploys
+        .iter()
+        .map(DeployTarget::local_path)
+        .collect();
+    for cron in config.crons.iter_mut().filter(|c| c.dir.is_none()) {
+        if deploy_dirs.contains(&cron.local_path()) {
+            cron.dir = Some(derive_local_path(&format!("cron-{}", cron.name())));
+        }
+    }
+}
+
+pub fn validate(config: &Config) -> Result<()> {
+    let mut edge_rules = std::collections::HashSet::new();
+    for deploy in &config.deploys {
+        if deploy.tag.is_some() && deploy.commit.is_some() {
+            bail!("deploy {} sets both tag and commit", deploy.name());
+        }
+        let edge = deploy.host.is_some() || deploy.path_prefix.is_some();
+        match (deploy.port, edge) {
+            (Some(_), true) => bail!(
+                "deploy {} sets a port and a host or path prefix",
+                deploy.name()
+            ),
+            (None, false) => bail!(
+                "deploy {} needs a port or a host or path prefix",
+                deploy.name()
+            ),
+            _ => {}
+        }
+        if edge {
+            if deploy.route.is_none() {
+                bail!(
+                    "deploy {} needs a route to use the edge proxy",
+                    deploy.name()
+                );
+            }
+            if deploy
+                .path_prefix
+                .as_ref()
+                .is_some_and(|prefix| !prefix.starts_with('/'))
+            {
+                bail!("deploy {} path prefix must start with /", deploy.name());
+            }
+            if !edge_rules.insert((&deploy.host, &deploy.path_prefix)) {
+                bail!(
+                    "deploy {} has the same host and path prefix as another deploy",
+                    deploy.name()
+                );
+            }
+        }
+        PathFilter::new(&deploy.include, &deploy.exclude)
+            .with_context(|| format!("deploy {}", deploy.name()))?;
+        for probe in &deploy.probes {
+            if matches!(&probe.check, Check::Exec { command, .. } if command.is_empty()) {
+                bail!(
+                    "deploy {} has an exec probe without a command",
+                    deploy.name()
+                );
+            }
+        }
+        if let Some(route) = &deploy.route
+            && deploy.shared.contains(&route.service)
+        {
+            bail!(
+                "deploy {} routes to shared service {}",
+                deploy.name(),
+                route.service
+            );
+        }
+        if deploy.drain.is_some() && deploy.route.is_none() {
+            bail!("deploy {} needs a route to drain", deploy.name());
+        }
+        // Labels would keep sending traffic to the old slot
+        if deploy.keep_previous == KeepPrevious::Run && deploy.route.is_none() {
+            bail!(
+                "deploy {} needs a route to keep the previous slot running",
+                deploy.name()
+            );
+        }
+        if let Some(tls) = &deploy.tls {
+            validate_tls(deploy, tls)?;
+        }
+        if let Some(canary) = &deploy.canary {
+            if deploy.route.is_none() {
+                bail!("deploy {} needs a route to use a canary", deploy.name());
+            }
+            if canary.steps.is_empty() {
+                bail!("deploy {} has a canary without steps", deploy.name());
+            }
+            if canary.steps.iter().any(|&w| w == 0 || w > 100)
+                || canary.steps.windows(2).any(|w| w[0] >= w[1])
+            {
+                bail!(
+                    "deploy {} canary steps must rise from 1 to at most 100",
+                    deploy.name()
+                );
+            }
+        }
+    }
+
+    let mut seen = std::collections::HashSet::new();
+    for name in config
+        .deploys
+        .iter()
+        .map(|d| format!("deploy {}", d.name()))
+        .chain(config.crons.iter().map(|c| format!("cron {}", c.name())))
+    {
+        if !seen.insert(name.clone()) {
+            bail!("duplicate {} in config", name);
+        }
+    }
+
+    // Each target owns its checkout, state and history
+    let mut dirs = std::collections::HashMap::new();
+    for (name, dir) in config
+        .deploys
+        .iter()
+        .map(|d| (format!("deploy {}", d.name()), d.local_path()))
+        .chain(
+            config
+                .crons
+                .iter()
+                .map(|c| (format!("cron {}", c.name()), c.local_path())),
+        )
+    {
+        if let Some(other) = dirs.insert(dir.clone(), name.clone()) {
+            bail!("{} and {} both use {}", other, name, dir.display());
+        }
+    }
+
+    Ok(())
+}
+
+fn validate_tls(deploy: &DeployTarget, tls: &Tls) -> Result<()> {
+    if deploy.route.is_none() {
+        bail!("deploy {} needs a route to use tls", deploy.name());
+    }
+    match (&tls.acme, &tls.cert, &tls.key) {
+        (Some(_), None, None) => {
+            if tls.domains.is_empty() && deploy.host.is_none() {
+                bail!("deploy {} needs tls domains for acme", deploy.name());
+            }
+        }
+        (None, Some(cert), Some(key)) => {
+            for path in [cert, key] {
+                if !path.is_file() {
+                    bail!(
+                        "deploy {}: {} does not exist",
+                        deploy.name(),
+                        path.display()
+                    );
+                }
+            }
+        }
+        _ => bail!(
+            "deploy {} tls needs either acme or both cert and key",
+            deploy.name()
+        ),
+    }
+    if let Some(ca_cert) = tls.acme.as_ref().and_then(|acme| acme.ca_cert.as_ref())
+        && !ca_cert.is_file()
+    {
+        bail!(
+            "deploy {}: {} does not exist",
+            deploy.name(),
+            ca_cert.display()
+        );
+    }
+    if deploy.port == Some(tls.port) {
+        bail!(
+            "deploy {} serves http and https on the same port",
+            deploy.name()
+        );
+    }
+    Ok(())
+}
+
+pub fn repo_name(repo: &str) -> String {
+    repo.split('/')
+        .next_back()
+        .unwrap_or("repo")
+        .trim_end_matches(".git")
+        .to_string()
+}
+
+/// Default checkout directory for a target called `name`
+pub fn derive_local_path(name: &str) -> PathBuf {
+    dirs::state_dir()
+        .unwrap_or_else(|| PathBuf::from("/var/lib"))
+        .join("rollploy")
+        .join(name)
+}
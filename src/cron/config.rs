@@ -14,7 +14,7 @@ pub struct Job {
     pub schedule: String,
 }
 
-const CONFIG_FILE: &str = "rollploy.cron.yml";
+pub const CONFIG_FILE: &str = "rollploy.cron.yml";
 
 pub fn load(repo_path: &Path) -> Result<CronConfig> {
     let config_path = repo_path.join(CONFIG_FILE);
@@ -1,4 +1,4 @@
 mod config;
 mod runner;
 
-pub use runner::{CronRunner, CronRunnerArgs};
+pub use runner::{CronRunner, CronRunnerArgs, Message};
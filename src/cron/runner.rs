@@ -1,5 +1,5 @@
 use super::config::{self, Job};
-use crate::git;
+use crate::git::{self, SyncError};
 use chrono::Utc;
 use cron::Schedule;
 use ractor::{async_trait, Actor, ActorProcessingErr, ActorRef};
@@ -15,16 +15,20 @@ pub struct CronRunner;
 
 pub struct CronRunnerArgs {
     pub repo_url: String,
-    pub branch: String,
+    pub git: git::Options,
     pub local_path: PathBuf,
     pub check_interval: Duration,
 }
 
 pub struct State {
     repo_url: String,
+    git: git::Options,
     local_path: PathBuf,
     jobs: Vec<JobState>,
     running: Arc<Mutex<HashMap<String, bool>>>,
+    /// Why the last pull failed, e.g. a diverged branch; repeated with
+    /// every job run until a pull succeeds
+    sync_error: Option<String>,
 }
 
 struct JobState {
@@ -52,7 +56,7 @@ impl Actor for CronRunner {
         info!(repo = %args.repo_url, "starting cron runner");
 
         // Clone repo
-        git::ensure_repo(&args.local_path, &args.repo_url, &args.branch)?;
+        git::ensure_repo(&args.local_path, &args.repo_url, &args.git).await?;
 
         // Load config
         let config = config::load(&args.local_path)?;
@@ -60,11 +64,17 @@ impl Actor for CronRunner {
 
         info!(job_count = jobs.len(), "loaded cron jobs");
 
+        // The jobs file sits at the root, outside any subdir
+        let mut git = args.git;
+        git.extra_paths.push(config::CONFIG_FILE.to_string());
+
         let state = State {
             repo_url: args.repo_url,
+            git,
             local_path: args.local_path,
             jobs,
             running: Arc::new(Mutex::new(HashMap::new())),
+            sync_error: None,
         };
 
         // Check every second for due jobs
@@ -104,6 +114,10 @@ impl Actor for CronRunner {
                             running.insert(job_name.clone(), true);
                         }
 
+                        if let Some(e) = &state.sync_error {
+                            warn!(job = %job_name, error = %e, "checkout needs attention, running job from the last pulled commit");
+                        }
+
                         // Run in background
                         let script = state.local_path.join(&job_state.job.script);
                         let cwd = state.local_path.clone();
@@ -140,8 +154,12 @@ impl Actor for CronRunner {
             }
             Message::GitPull => {
                 info!(repo = %state.repo_url, "checking for updates");
-                match git::pull(&state.local_path) {
-                    Ok(true) => {
+                let result = git::pull(&state.local_path, &state.git).await;
+                if result.is_ok() && state.sync_error.take().is_some() {
+                    info!(repo = %state.repo_url, "checkout recovered");
+                }
+                match result {
+                    Ok(Some(_)) => {
                         info!("updates found, reloading config");
                         match config::load(&state.local_path) {
                             Ok(config) => match parse_jobs(config.jobs) {
@@ -154,11 +172,16 @@ impl Actor for CronRunner {
                             Err(e) => error!(error = %e, "failed to load config"),
                         }
                     }
-                    Ok(false) => {
+                    Ok(None) => {
                         info!("no updates");
                     }
                     Err(e) => {
-                        error!(error = %e, "git pull failed");
+                        if e.downcast_ref::<SyncError>().is_some() {
+                            error!(error = %e, "checkout needs attention, keeping current jobs until it is fixed");
+                        } else {
+                            error!(error = %e, "git pull failed");
+                        }
+                        state.sync_error = Some(format!("{:#}", e));
                     }
                 }
             }
@@ -1,73 +1,145 @@
+use crate::process::{self, CommandError, Output};
 use anyhow::{bail, Context, Result};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::net::IpAddr;
 use std::path::Path;
-use std::process::Command;
 use std::time::{Duration, Instant};
+use tokio::process::Command;
 
-pub fn compose_up(cwd: &Path, compose_files: &[&str], project: &str, network: &str) -> Result<()> {
+/// Quick queries and state changes
+const DOCKER_TIMEOUT: Duration = Duration::from_secs(60);
+/// `compose up` may pull and build images
+const COMPOSE_UP_TIMEOUT: Duration = Duration::from_secs(1800);
+/// Restarts tolerated while waiting before a container counts as crash-looping
+const MAX_RESTARTS: u32 = 2;
+/// Log lines attached to errors about a failed container
+const LOG_TAIL: usize = 20;
+
+/// Start a project and attach its containers to `network`. With `services`,
+/// only those are started, without their dependencies.
+pub async fn compose_up(
+    cwd: &Path,
+    compose_files: &[&str],
+    project: &str,
+    network: &str,
+    services: &[String],
+    pull: &str,
+) -> Result<()> {
     let mut args = vec!["compose", "-p", project];
     for f in compose_files {
         args.push("-f");
         args.push(f);
     }
-    args.extend(["up", "-d", "--pull", "always"]);
-
-    let status = Command::new("docker")
-        .args(&args)
-        .current_dir(cwd)
-        .status()
-        .context("docker compose up failed")?;
-
-    if !status.success() {
-        bail!("docker compose up exited with {}", status);
+    args.extend(["up", "-d", "--pull", pull]);
+    if !services.is_empty() {
+        args.push("--no-deps");
+        args.extend(services.iter().map(String::as_str));
     }
 
+    process::run(
+        Command::new("docker").args(&args).current_dir(cwd),
+        COMPOSE_UP_TIMEOUT,
+    )
+    .await?;
+
     // Connect to network
-    connect_to_network(project, network)?;
+    connect_to_network(project, network).await?;
 
     Ok(())
 }
 
-pub fn compose_down(cwd: &Path, compose_files: &[&str], project: &str) -> Result<()> {
-    let mut args = vec!["compose", "-p", project];
+/// Names of the services the compose files define
+pub async fn compose_services(cwd: &Path, compose_files: &[&str]) -> Result<Vec<String>> {
+    let mut args = vec!["compose"];
     for f in compose_files {
         args.push("-f");
         args.push(f);
     }
-    args.push("down");
+    args.extend(["config", "--services"]);
+
+    let output = process::run(
+        Command::new("docker").args(&args).current_dir(cwd),
+        DOCKER_TIMEOUT,
+    )
+    .await?;
+    Ok(output
+        .stdout
+        .lines()
+        .filter(|name| !name.is_empty())
+        .map(str::to_string)
+        .collect())
+}
+
+pub async fn compose_down(cwd: &Path, compose_files: &[&str], project: &str) -> Result<()> {
+    compose(cwd, compose_files, project, "down").await
+}
 
-    let status = Command::new("docker")
-        .args(&args)
-        .current_dir(cwd)
-        .status()
-        .context("docker compose down failed")?;
+/// Stop the containers of a project, keeping them around to start again
+pub async fn compose_stop(cwd: &Path, compose_files: &[&str], project: &str) -> Result<()> {
+    compose(cwd, compose_files, project, "stop").await
+}
 
-    if !status.success() {
-        bail!("docker compose down exited with {}", status);
+/// Stop the running containers of a project with `signal`, killing those
+/// still running after `grace`
+pub async fn stop_with_signal(project: &str, signal: &str, grace: Duration) -> Result<()> {
+    let output = docker(&["compose", "-p", project, "ps", "-q"]).await?;
+    let ids: Vec<&str> = output.stdout.lines().filter(|id| !id.is_empty()).collect();
+    if ids.is_empty() {
+        return Ok(());
     }
+
+    let time = grace.as_secs().to_string();
+    let mut args = vec!["stop", "--signal", signal, "--time", &time];
+    args.extend(ids);
+    process::run(Command::new("docker").args(&args), DOCKER_TIMEOUT + grace).await?;
     Ok(())
 }
 
-fn connect_to_network(project: &str, network: &str) -> Result<()> {
+pub async fn compose_start(cwd: &Path, compose_files: &[&str], project: &str) -> Result<()> {
+    compose(cwd, compose_files, project, "start").await
+}
+
+async fn compose(cwd: &Path, compose_files: &[&str], project: &str, action: &str) -> Result<()> {
+    let mut args = vec!["compose", "-p", project];
+    for f in compose_files {
+        args.push("-f");
+        args.push(f);
+    }
+    args.push(action);
+
+    process::run(
+        Command::new("docker").args(&args).current_dir(cwd),
+        DOCKER_TIMEOUT,
+    )
+    .await?;
+    Ok(())
+}
+
+async fn connect_to_network(project: &str, network: &str) -> Result<()> {
     // Get all containers in the project
-    let output = Command::new("docker")
-        .args(["compose", "-p", project, "ps", "-q"])
-        .output()
-        .context("docker compose ps failed")?;
+    let output = docker(&["compose", "-p", project, "ps", "-q"]).await?;
 
-    let container_ids = String::from_utf8_lossy(&output.stdout);
-    for id in container_ids.lines() {
+    for id in output.stdout.lines() {
         if id.is_empty() {
             continue;
         }
-        // Connect container to network (ignore if already connected)
-        let _ = Command::new("docker")
-            .args(["network", "connect", network, id])
-            .status();
+        connect(network, id).await?;
     }
     Ok(())
 }
 
-pub fn wait_healthy(project: &str, timeout: Duration) -> Result<()> {
+/// Attach a container to a network it may already be on
+pub async fn connect(network: &str, container: &str) -> Result<()> {
+    match docker(&["network", "connect", network, container]).await {
+        Ok(_) => Ok(()),
+        // Containers that restarted keep their earlier connection
+        Err(CommandError::Failed { stderr, .. }) if stderr.contains("already exists") => Ok(()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+pub async fn wait_healthy(project: &str, timeout: Duration) -> Result<()> {
     let start = Instant::now();
 
     loop {
@@ -75,62 +147,231 @@ pub fn wait_healthy(project: &str, timeout: Duration) -> Result<()> {
             bail!("health check timeout for project {}", project);
         }
 
-        // Get all containers in project with health status
-        let output = Command::new("docker")
-            .args([
-                "compose",
-                "-p",
-                project,
-                "ps",
-                "--format",
-                "{{.Health}}",
-            ])
-            .output()
-            .context("docker compose ps failed")?;
-
-        let statuses = String::from_utf8_lossy(&output.stdout);
-        let statuses: Vec<&str> = statuses.lines().filter(|s| !s.is_empty()).collect();
-
-        if statuses.is_empty() {
-            std::thread::sleep(Duration::from_secs(2));
+        let containers = project_containers(project).await?;
+        if containers.is_empty() {
+            tokio::time::sleep(Duration::from_secs(2)).await;
             continue;
         }
 
-        let all_healthy = statuses.iter().all(|s| *s == "healthy" || s.is_empty());
-        let any_unhealthy = statuses.iter().any(|s| *s == "unhealthy");
-
-        if any_unhealthy {
-            bail!("container in project {} is unhealthy", project);
+        let mut all_healthy = true;
+        for container in &containers {
+            match container.verdict() {
+                Verdict::Ready => {}
+                Verdict::Pending => all_healthy = false,
+                Verdict::Failed(reason) => return Err(failure(container, project, &reason).await),
+            }
         }
 
         if all_healthy {
             return Ok(());
         }
 
-        std::thread::sleep(Duration::from_secs(2));
+        tokio::time::sleep(Duration::from_secs(2)).await;
     }
 }
 
-pub fn network_exists(name: &str) -> bool {
-    Command::new("docker")
-        .args(["network", "inspect", name])
-        .output()
-        .map(|o| o.status.success())
-        .unwrap_or(false)
+/// Restart counts of the containers of a project, to compare against in [`check_stable`]
+pub async fn restart_counts(project: &str) -> Result<HashMap<String, u32>> {
+    Ok(project_containers(project)
+        .await?
+        .into_iter()
+        .map(|c| (c.id, c.restart_count))
+        .collect())
 }
 
-pub fn create_network(name: &str) -> Result<()> {
-    if network_exists(name) {
-        return Ok(());
+/// Fail if a container of a live project stopped, became unhealthy or
+/// restarted since `baseline` was taken
+pub async fn check_stable(project: &str, baseline: &HashMap<String, u32>) -> Result<()> {
+    let containers = project_containers(project).await?;
+    if containers.is_empty() {
+        bail!("project {} has no containers left", project);
+    }
+    for container in &containers {
+        let restarts = container
+            .restart_count
+            .saturating_sub(baseline.get(&container.id).copied().unwrap_or(0));
+        let reason = match container.verdict() {
+            Verdict::Failed(reason) => reason,
+            _ if restarts > 0 => format!("restarted (restart count up by {})", restarts),
+            // Restarting, paused or back to starting
+            Verdict::Pending => "is not running".to_string(),
+            Verdict::Ready => continue,
+        };
+        return Err(failure(container, project, &reason).await);
+    }
+    Ok(())
+}
+
+/// The parts of `docker inspect` the health checks look at
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct Container {
+    id: String,
+    name: String,
+    restart_count: u32,
+    state: ContainerState,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct ContainerState {
+    status: String,
+    exit_code: i64,
+    #[serde(rename = "OOMKilled")]
+    oom_killed: bool,
+    health: Option<ContainerHealth>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct ContainerHealth {
+    status: String,
+}
+
+enum Verdict {
+    Ready,
+    Pending,
+    Failed(String),
+}
+
+impl Container {
+    fn verdict(&self) -> Verdict {
+        let state = &self.state;
+        if state.oom_killed {
+            return Verdict::Failed("was killed for running out of memory".to_string());
+        }
+        if self.restart_count > MAX_RESTARTS {
+            return Verdict::Failed(format!(
+                "is restart-looping ({} restarts, last exit code {})",
+                self.restart_count, state.exit_code
+            ));
+        }
+        match state.status.as_str() {
+            // One-shot services such as migrations finish with 0
+            "exited" if state.exit_code == 0 => Verdict::Ready,
+            "exited" | "dead" => Verdict::Failed(format!("exited with code {}", state.exit_code)),
+            "running" => match state.health.as_ref().map(|h| h.status.as_str()) {
+                None | Some("healthy") => Verdict::Ready,
+                Some("unhealthy") => Verdict::Failed("is unhealthy".to_string()),
+                Some(_) => Verdict::Pending,
+            },
+            // created, restarting, paused
+            _ => Verdict::Pending,
+        }
     }
+}
 
-    let status = Command::new("docker")
-        .args(["network", "create", name])
-        .status()
-        .context("docker network create failed")?;
+/// Every container of a project, including stopped ones so crashes are noticed
+async fn project_containers(project: &str) -> Result<Vec<Container>> {
+    let output = docker(&["compose", "-p", project, "ps", "-a", "-q"]).await?;
+    let ids: Vec<&str> = output.stdout.lines().filter(|id| !id.is_empty()).collect();
+    if ids.is_empty() {
+        return Ok(Vec::new());
+    }
 
-    if !status.success() {
-        bail!("docker network create exited with {}", status);
+    let mut args = vec!["inspect"];
+    args.extend(ids);
+    let output = docker(&args).await?;
+    serde_json::from_str(&output.stdout).context("failed to parse docker inspect output")
+}
+
+/// Error for a failed container, with its last log lines attached
+async fn failure(container: &Container, project: &str, reason: &str) -> anyhow::Error {
+    let logs = logs_tail(&container.id).await;
+    anyhow::anyhow!(
+        "container {} in project {} {}\n--- last {} log lines ---\n{}",
+        container.name.trim_start_matches('/'),
+        project,
+        reason,
+        LOG_TAIL,
+        logs
+    )
+}
+
+/// Last log lines of a container for error messages, or why they are missing
+async fn logs_tail(id: &str) -> String {
+    let tail = LOG_TAIL.to_string();
+    match docker(&["logs", "--tail", &tail, id]).await {
+        // Containers log to both streams; their order across streams is lost
+        Ok(output) => format!("{}{}", output.stdout, output.stderr)
+            .trim_end()
+            .to_string(),
+        Err(e) => format!("(logs unavailable: {})", e),
     }
+}
+
+/// IDs of the running containers of one compose service
+pub async fn service_containers(project: &str, service: &str) -> Result<Vec<String>> {
+    let output = docker(&["compose", "-p", project, "ps", "-q", service]).await?;
+    Ok(output
+        .stdout
+        .lines()
+        .filter(|id| !id.is_empty())
+        .map(String::from)
+        .collect())
+}
+
+/// Names of the running containers of one compose service
+pub async fn service_container_names(project: &str, service: &str) -> Result<Vec<String>> {
+    let output = docker(&[
+        "compose",
+        "-p",
+        project,
+        "ps",
+        "--format",
+        "{{.Name}}",
+        service,
+    ])
+    .await?;
+    Ok(output
+        .stdout
+        .lines()
+        .filter(|name| !name.is_empty())
+        .map(String::from)
+        .collect())
+}
+
+/// Address of a container on `network`
+pub async fn container_ip(id: &str, network: &str) -> Result<IpAddr> {
+    let format = format!(
+        "{{{{with index .NetworkSettings.Networks \"{}\"}}}}{{{{.IPAddress}}}}{{{{end}}}}",
+        network
+    );
+    let output = docker(&["inspect", "-f", &format, id]).await?;
+    let ip = output.stdout.trim();
+    ip.parse()
+        .with_context(|| format!("container {} has no address on {}", id, network))
+}
+
+/// Run a command inside a running container
+pub async fn exec(id: &str, command: &[String]) -> Result<Output, CommandError> {
+    let mut args = vec!["exec", id];
+    args.extend(command.iter().map(String::as_str));
+    docker(&args).await
+}
+
+/// The network an app's slots and its Traefik share
+pub fn network_name(app_name: &str) -> String {
+    format!("rollploy-{}", app_name)
+}
+
+pub async fn network_exists(name: &str) -> Result<bool> {
+    match docker(&["network", "inspect", name]).await {
+        Ok(_) => Ok(true),
+        Err(CommandError::Failed { .. }) => Ok(false),
+        Err(e) => Err(e.into()),
+    }
+}
+
+pub async fn create_network(name: &str) -> Result<()> {
+    if network_exists(name).await? {
+        return Ok(());
+    }
+
+    docker(&["network", "create", name]).await?;
     Ok(())
 }
+
+pub async fn docker(args: &[&str]) -> Result<Output, CommandError> {
+    process::run(Command::new("docker").args(args), DOCKER_TIMEOUT).await
+}
@@ -1,38 +1,460 @@
-use anyhow::{Context, Result};
-use std::path::Path;
-use std::process::Command;
+use crate::process::{self, Output};
+use anyhow::{bail, Context, Result};
+use serde::Deserialize;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tokio::process::Command;
+use tracing::{info, warn};
 
-pub fn ensure_repo(local_path: &Path, url: &str, branch: &str) -> Result<()> {
+/// Upper bound for any single git invocation, including network operations
+const GIT_TIMEOUT: Duration = Duration::from_secs(300);
+
+/// What the checkout follows
+#[derive(Debug, Clone, PartialEq)]
+pub enum Track {
+    /// Tip of a branch
+    Branch(String),
+    /// Highest version tag matching a glob such as `v*`
+    Tag(String),
+    /// One exact commit
+    Commit(String),
+}
+
+impl fmt::Display for Track {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Track::Branch(branch) => write!(f, "branch {}", branch),
+            Track::Tag(pattern) => write!(f, "tag {}", pattern),
+            Track::Commit(sha) => write!(f, "commit {}", sha),
+        }
+    }
+}
+
+/// What to do when the checkout can no longer fast-forward to the remote
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum DivergePolicy {
+    /// Hard reset to the remote, discarding local commits and changes
+    Reset,
+    /// Stop updating and report the problem until someone fixes the checkout
+    #[default]
+    Halt,
+}
+
+/// Checkout states that block an update, kept distinct so callers can report them
+#[derive(Debug, thiserror::Error)]
+pub enum SyncError {
+    #[error("local {local} and origin/{branch} {remote} have diverged (force-push?)")]
+    Diverged {
+        branch: String,
+        local: String,
+        remote: String,
+    },
+    #[error("worktree has uncommitted changes: {files}")]
+    DirtyWorktree { files: String },
+}
+
+/// Credentials for one repository, applied per git invocation so they never
+/// leak into the host's global git or ssh config
+#[derive(Debug, Clone, PartialEq, Default, Deserialize, clap::Args)]
+pub struct Auth {
+    /// SSH private key for ssh:// and scp-style remotes
+    #[arg(long)]
+    pub ssh_key: Option<PathBuf>,
+
+    /// known_hosts file to verify the SSH host key against
+    #[arg(long)]
+    pub known_hosts: Option<PathBuf>,
+
+    /// Trust and record unknown SSH host keys on first connect
+    #[arg(long)]
+    #[serde(default)]
+    pub accept_new_host_keys: bool,
+
+    /// File holding an HTTPS access token
+    #[arg(long, conflicts_with = "token_env")]
+    pub token_file: Option<PathBuf>,
+
+    /// Environment variable holding an HTTPS access token
+    #[arg(long)]
+    pub token_env: Option<String>,
+
+    /// Username sent along with the token [default: x-access-token]
+    #[arg(long)]
+    pub token_user: Option<String>,
+}
+
+impl Auth {
+    /// Add credential config and environment to a git command. Must run
+    /// before the subcommand is added, since `-c` is a global option.
+    fn apply(&self, cmd: &mut Command) -> Result<()> {
+        // Host key settings also apply to keys from an agent
+        if self.ssh_key.is_some() || self.known_hosts.is_some() || self.accept_new_host_keys {
+            let mut ssh = "ssh -o BatchMode=yes".to_string();
+            if let Some(key) = &self.ssh_key {
+                ssh.push_str(&format!(
+                    " -i {} -o IdentitiesOnly=yes",
+                    shell_quote(&key.to_string_lossy())
+                ));
+            }
+            if let Some(known_hosts) = &self.known_hosts {
+                ssh.push_str(&format!(
+                    " -o UserKnownHostsFile={}",
+                    shell_quote(&known_hosts.to_string_lossy())
+                ));
+            }
+            let strict = if self.accept_new_host_keys {
+                "accept-new"
+            } else {
+                "yes"
+            };
+            ssh.push_str(&format!(" -o StrictHostKeyChecking={}", strict));
+            cmd.env("GIT_SSH_COMMAND", ssh);
+        }
+
+        if let Some(token) = self.token()? {
+            let user = self.token_user.as_deref().unwrap_or("x-access-token");
+            // The token stays in the environment, out of argv and out of .git/config.
+            // The empty helper drops any helpers configured on the host.
+            cmd.env("ROLLPLOY_GIT_USER", user)
+                .env("ROLLPLOY_GIT_TOKEN", token)
+                .args([
+                    "-c",
+                    "credential.helper=",
+                    "-c",
+                    "credential.helper=!f() { test \"$1\" = get || return 0; echo \"username=$ROLLPLOY_GIT_USER\"; echo \"password=$ROLLPLOY_GIT_TOKEN\"; }; f",
+                ]);
+        }
+        Ok(())
+    }
+
+    fn token(&self) -> Result<Option<String>> {
+        if let Some(path) = &self.token_file {
+            let token = std::fs::read_to_string(path)
+                .with_context(|| format!("failed to read token file {}", path.display()))?;
+            return Ok(Some(token.trim().to_string()));
+        }
+        if let Some(var) = &self.token_env {
+            let token =
+                std::env::var(var).with_context(|| format!("token variable {} is not set", var))?;
+            return Ok(Some(token.trim().to_string()));
+        }
+        Ok(None)
+    }
+}
+
+fn shell_quote(s: &str) -> String {
+    format!("'{}'", s.replace('\'', "'\\''"))
+}
+
+/// Everything that decides how a repository is checked out and updated
+#[derive(Debug, Clone, PartialEq)]
+pub struct Options {
+    pub track: Track,
+    pub on_diverge: DivergePolicy,
+    pub auth: Auth,
+    /// Init and update submodules recursively after every update
+    pub submodules: bool,
+    /// Only check out this directory, and only report updates touching it
+    pub subdir: Option<String>,
+    /// Files at the repository root whose changes are reported as well when
+    /// `subdir` is set
+    pub extra_paths: Vec<String>,
+}
+
+pub async fn ensure_repo(local_path: &Path, url: &str, opts: &Options) -> Result<()> {
     if local_path.exists() {
+        // Sparse settings may have changed since the clone
+        apply_sparse(local_path, opts).await?;
         return Ok(());
     }
-    Command::new("git")
-        .args(["clone", "--branch", branch, "--single-branch", url])
-        .arg(local_path)
-        .status()
-        .context("git clone failed")?;
+
+    let mut cmd = Command::new("git");
+    cmd.env("GIT_TERMINAL_PROMPT", "0");
+    opts.auth.apply(&mut cmd)?;
+    match &opts.track {
+        Track::Branch(branch) => {
+            cmd.args([
+                "clone",
+                "--no-checkout",
+                "--branch",
+                branch,
+                "--single-branch",
+                url,
+            ]);
+        }
+        // Tags and pinned commits may live on any branch
+        Track::Tag(_) | Track::Commit(_) => {
+            cmd.args(["clone", "--no-checkout", url]);
+        }
+    }
+    process::run(cmd.arg(local_path), GIT_TIMEOUT).await?;
+
+    // Set up sparse checkout before the first checkout populates the worktree
+    apply_sparse(local_path, opts).await?;
+    let target = resolve(local_path, opts).await?;
+    checkout(local_path, &target, opts).await?;
+    Ok(())
+}
+
+/// Commits and files a pull brought in
+#[derive(Debug)]
+pub struct Update {
+    pub from: String,
+    pub to: String,
+    /// Paths relative to the repository root, limited to `subdir` if set
+    pub files: Vec<String>,
+}
+
+/// Move the checkout to the latest revision of the tracked ref.
+/// Returns the update if there was one (under `subdir`, if set). A diverged
+/// branch or dirty worktree fails with a [`SyncError`] unless the policy says
+/// to reset.
+pub async fn pull(local_path: &Path, opts: &Options) -> Result<Option<Update>> {
+    let before = head(local_path).await?;
+    let policy = opts.on_diverge;
+
+    let dirty = dirty_files(local_path).await?;
+    if !dirty.is_empty() {
+        if policy == DivergePolicy::Halt {
+            return Err(SyncError::DirtyWorktree {
+                files: dirty.join(", "),
+            }
+            .into());
+        }
+        warn!(path = %local_path.display(), files = %dirty.join(", "), "discarding local changes");
+        reset_hard(local_path, "HEAD").await?;
+    }
+
+    match &opts.track {
+        Track::Branch(branch) => {
+            remote_git(local_path, &opts.auth, &["fetch", "origin", branch]).await?;
+
+            let remote_ref = format!("refs/remotes/origin/{}", branch);
+            let remote = rev_parse(local_path, &remote_ref).await?;
+            if remote != before {
+                if is_ancestor(local_path, &before, &remote).await? {
+                    git(local_path, &["merge", "--ff-only", "--quiet", &remote_ref]).await?;
+                } else if policy == DivergePolicy::Reset {
+                    warn!(branch = %branch, local = %before, remote = %remote, "branch diverged, resetting to remote");
+                    reset_hard(local_path, &remote_ref).await?;
+                } else {
+                    return Err(SyncError::Diverged {
+                        branch: branch.clone(),
+                        local: before,
+                        remote,
+                    }
+                    .into());
+                }
+            }
+        }
+        Track::Tag(_) | Track::Commit(_) => {
+            let target = resolve(local_path, opts).await?;
+            if rev_parse(local_path, &format!("{}^{{commit}}", target)).await? != before {
+                git(local_path, &["checkout", "--quiet", &target]).await?;
+            }
+        }
+    }
+
+    if opts.submodules {
+        update_submodules(local_path, &opts.auth).await?;
+    }
+
+    let after = head(local_path).await?;
+    update(local_path, opts, before, after).await
+}
+
+/// Fetch and report what a pull would bring in on top of `from`, without
+/// touching the worktree
+pub async fn peek(local_path: &Path, opts: &Options, from: &str) -> Result<Option<Update>> {
+    let to = match &opts.track {
+        Track::Branch(branch) => {
+            remote_git(local_path, &opts.auth, &["fetch", "origin", branch]).await?;
+            rev_parse(local_path, &format!("refs/remotes/origin/{}", branch)).await?
+        }
+        Track::Tag(_) | Track::Commit(_) => {
+            let target = resolve(local_path, opts).await?;
+            rev_parse(local_path, &format!("{}^{{commit}}", target)).await?
+        }
+    };
+    update(local_path, opts, from.to_string(), to).await
+}
+
+/// Describe the move from one commit to another, if it changes anything under `subdir`
+async fn update(
+    local_path: &Path,
+    opts: &Options,
+    from: String,
+    to: String,
+) -> Result<Option<Update>> {
+    if from == to {
+        return Ok(None);
+    }
+    let files = changed_files(local_path, &from, &to, opts).await?;
+    if let (Some(subdir), true) = (&opts.subdir, files.is_empty()) {
+        info!(from = %from, to = %to, subdir = %subdir, "no changes under subdir");
+        return Ok(None);
+    }
+    Ok(Some(Update { from, to, files }))
+}
+
+/// Files that differ between two commits, only under `subdir` and the extra
+/// paths if set
+async fn changed_files(
+    local_path: &Path,
+    from: &str,
+    to: &str,
+    opts: &Options,
+) -> Result<Vec<String>> {
+    let mut args = vec!["diff", "--name-only", "-z", from, to];
+    if let Some(subdir) = &opts.subdir {
+        args.extend(["--", subdir]);
+        args.extend(opts.extra_paths.iter().map(String::as_str));
+    }
+    let output = git(local_path, &args).await?;
+    Ok(output
+        .stdout
+        .split('\0')
+        .filter(|f| !f.is_empty())
+        .map(String::from)
+        .collect())
+}
+
+async fn apply_sparse(local_path: &Path, opts: &Options) -> Result<()> {
+    match &opts.subdir {
+        Some(subdir) => git(local_path, &["sparse-checkout", "set", "--cone", subdir]).await?,
+        None => git(local_path, &["sparse-checkout", "disable"]).await?,
+    };
     Ok(())
 }
 
-/// Returns true if there were updates
-pub fn pull(local_path: &Path) -> Result<bool> {
-    let before = get_head(local_path)?;
+async fn update_submodules(local_path: &Path, auth: &Auth) -> Result<()> {
+    // Pick up URL changes in .gitmodules before fetching
+    git(local_path, &["submodule", "sync", "--recursive", "--quiet"]).await?;
+    remote_git(
+        local_path,
+        auth,
+        &["submodule", "update", "--init", "--recursive", "--quiet"],
+    )
+    .await?;
+    Ok(())
+}
+
+/// Fetch and find the revision a tag or commit track points at
+async fn resolve(local_path: &Path, opts: &Options) -> Result<String> {
+    let auth = &opts.auth;
+    match &opts.track {
+        Track::Branch(branch) => Ok(branch.clone()),
+        Track::Tag(pattern) => {
+            fetch(local_path, auth).await?;
+            // Version sort so v1.10.0 beats v1.9.0, with pre-releases such as
+            // v2.0.0-rc1 below their release
+            let output = git(
+                local_path,
+                &[
+                    "-c",
+                    "versionsort.suffix=-",
+                    "tag",
+                    "--list",
+                    pattern,
+                    "--sort=-v:refname",
+                ],
+            )
+            .await?;
+            match output.stdout.lines().next() {
+                Some(tag) => Ok(format!("refs/tags/{}", tag.trim())),
+                None => bail!("no tags match {}", pattern),
+            }
+        }
+        Track::Commit(sha) => {
+            // Skip the network round trip once the commit is known
+            if rev_parse(local_path, &format!("{}^{{commit}}", sha))
+                .await
+                .is_err()
+            {
+                fetch(local_path, auth).await?;
+            }
+            Ok(sha.clone())
+        }
+    }
+}
 
-    Command::new("git")
-        .args(["pull", "--ff-only"])
-        .current_dir(local_path)
-        .status()
-        .context("git pull failed")?;
+/// Tracked files with uncommitted changes; rollploy's own untracked files are ignored
+async fn dirty_files(local_path: &Path) -> Result<Vec<String>> {
+    let output = git(
+        local_path,
+        &["status", "--porcelain", "--untracked-files=no"],
+    )
+    .await?;
+    Ok(output
+        .stdout
+        .lines()
+        .map(|line| line.get(3..).unwrap_or(line).to_string())
+        .collect())
+}
+
+async fn is_ancestor(local_path: &Path, ancestor: &str, descendant: &str) -> Result<bool> {
+    // 1 means "not an ancestor", anything else is a real failure
+    match git(
+        local_path,
+        &["merge-base", "--is-ancestor", ancestor, descendant],
+    )
+    .await
+    {
+        Ok(_) => Ok(true),
+        Err(e) if e.code() == Some(1) => Ok(false),
+        Err(e) => Err(e.into()),
+    }
+}
+
+async fn reset_hard(local_path: &Path, rev: &str) -> Result<()> {
+    git(local_path, &["reset", "--hard", "--quiet", rev]).await?;
+    Ok(())
+}
+
+async fn fetch(local_path: &Path, auth: &Auth) -> Result<()> {
+    remote_git(local_path, auth, &["fetch", "--tags", "--force", "origin"]).await?;
+    Ok(())
+}
+
+/// Check out `rev`, detaching HEAD if it is not a branch name
+pub async fn checkout(local_path: &Path, rev: &str, opts: &Options) -> Result<()> {
+    git(local_path, &["checkout", "--quiet", rev]).await?;
+    if opts.submodules {
+        update_submodules(local_path, &opts.auth).await?;
+    }
+    Ok(())
+}
+
+pub async fn head(local_path: &Path) -> Result<String> {
+    rev_parse(local_path, "HEAD").await
+}
+
+async fn rev_parse(local_path: &Path, rev: &str) -> Result<String> {
+    match git(local_path, &["rev-parse", "--verify", "--quiet", rev]).await {
+        Ok(output) => Ok(output.stdout.trim().to_string()),
+        Err(e) if e.code() == Some(1) => bail!("unknown revision {}", rev),
+        Err(e) => Err(e.into()),
+    }
+}
 
-    let after = get_head(local_path)?;
-    Ok(before != after)
+/// Run a git command that talks to the remote
+async fn remote_git(local_path: &Path, auth: &Auth, args: &[&str]) -> Result<Output> {
+    let mut cmd = Command::new("git");
+    cmd.current_dir(local_path).env("GIT_TERMINAL_PROMPT", "0");
+    auth.apply(&mut cmd)?;
+    Ok(process::run(cmd.args(args), GIT_TIMEOUT).await?)
 }
 
-fn get_head(local_path: &Path) -> Result<String> {
-    let output = Command::new("git")
-        .args(["rev-parse", "HEAD"])
-        .current_dir(local_path)
-        .output()
-        .context("git rev-parse failed")?;
-    Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+async fn git(local_path: &Path, args: &[&str]) -> Result<Output, process::CommandError> {
+    // Fail instead of waiting for a credential prompt nobody will answer
+    process::run(
+        Command::new("git")
+            .args(args)
+            .current_dir(local_path)
+            .env("GIT_TERMINAL_PROMPT", "0"),
+        GIT_TIMEOUT,
+    )
+    .await
 }
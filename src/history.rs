@@ -0,0 +1,99 @@
+use crate::state::Slot;
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::io::Write;
+use std::path::Path;
+
+/// One deploy attempt, stored as a line of JSON
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Entry {
+    pub sha: Option<String>,
+    pub slot: Slot,
+    pub kind: Kind,
+    pub started_at: DateTime<Utc>,
+    pub finished_at: DateTime<Utc>,
+    pub outcome: Outcome,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    /// The new slot went live and the old one was brought back, after a
+    /// failed or aborted bake
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub reverted: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Kind {
+    /// Bringing up the active slot on startup
+    Initial,
+    Deploy,
+    Rollback,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Outcome {
+    Success,
+    Failure,
+    /// Aborted by an operator or superseded by a newer commit
+    Cancelled,
+}
+
+impl Kind {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Kind::Initial => "initial",
+            Kind::Deploy => "deploy",
+            Kind::Rollback => "rollback",
+        }
+    }
+}
+
+impl Outcome {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Outcome::Success => "success",
+            Outcome::Failure => "failure",
+            Outcome::Cancelled => "cancelled",
+        }
+    }
+}
+
+const HISTORY_FILE: &str = ".rollploy-history.jsonl";
+
+/// Append `entry`, dropping the oldest entries beyond `limit`
+pub fn append(dir: &Path, entry: &Entry, limit: usize) -> Result<()> {
+    let path = dir.join(HISTORY_FILE);
+    let line = serde_json::to_string(entry).context("failed to serialize history entry")?;
+
+    let mut file = std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&path)
+        .context("failed to open history file")?;
+    writeln!(file, "{}", line).context("failed to write history file")?;
+    drop(file);
+
+    let content = std::fs::read_to_string(&path).context("failed to read history file")?;
+    let lines: Vec<&str> = content.lines().collect();
+    if lines.len() > limit {
+        let mut kept = lines[lines.len() - limit..].join("\n");
+        kept.push('\n');
+        std::fs::write(&path, kept).context("failed to trim history file")?;
+    }
+    Ok(())
+}
+
+/// All entries, oldest first. Unparseable lines are skipped.
+pub fn load(dir: &Path) -> Result<Vec<Entry>> {
+    let path = dir.join(HISTORY_FILE);
+    if !path.exists() {
+        return Ok(Vec::new());
+    }
+    let content = std::fs::read_to_string(&path).context("failed to read history file")?;
+    Ok(content
+        .lines()
+        .filter_map(|line| serde_json::from_str(line).ok())
+        .collect())
+}
@@ -0,0 +1,73 @@
+use anyhow::{bail, Context, Result};
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/// Minimal HTTP/1.0 client for talking to local services
+pub enum Target {
+    Tcp(SocketAddr),
+    Unix(PathBuf),
+}
+
+pub struct Response {
+    pub status: u16,
+    pub body: String,
+}
+
+pub async fn request(target: &Target, method: &str, path: &str) -> Result<Response> {
+    // HTTP/1.0 keeps responses unchunked and closes the connection when done
+    let request = format!(
+        "{} {} HTTP/1.0\r\nHost: localhost\r\nContent-Length: 0\r\n\r\n",
+        method, path
+    );
+
+    let raw = match target {
+        Target::Tcp(addr) => {
+            let stream = tokio::net::TcpStream::connect(addr)
+                .await
+                .with_context(|| format!("failed to connect to {}", addr))?;
+            exchange(stream, &request).await?
+        }
+        Target::Unix(path) => {
+            let stream = tokio::net::UnixStream::connect(path)
+                .await
+                .with_context(|| format!("failed to connect to {}", path.display()))?;
+            exchange(stream, &request).await?
+        }
+    };
+
+    parse(&raw)
+}
+
+async fn exchange<S>(mut stream: S, request: &str) -> Result<Vec<u8>>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    stream
+        .write_all(request.as_bytes())
+        .await
+        .context("failed to send request")?;
+    let mut raw = Vec::new();
+    stream
+        .read_to_end(&mut raw)
+        .await
+        .context("failed to read response")?;
+    Ok(raw)
+}
+
+fn parse(raw: &[u8]) -> Result<Response> {
+    let raw = String::from_utf8_lossy(raw);
+    let (head, body) = raw.split_once("\r\n\r\n").unwrap_or((&raw, ""));
+    let status_line = head.lines().next().unwrap_or_default();
+    let Some(status) = status_line
+        .split_whitespace()
+        .nth(1)
+        .and_then(|code| code.parse().ok())
+    else {
+        bail!("malformed HTTP response: {:?}", status_line);
+    };
+    Ok(Response {
+        status,
+        body: body.to_string(),
+    })
+}
@@ -1,15 +1,29 @@
 mod actors;
+mod api;
+mod config;
 mod cron;
 mod docker;
 mod git;
+mod history;
+mod http;
+mod paths;
+mod probe;
+mod process;
 mod state;
 mod traefik;
+mod webhook;
 
-use actors::{Deployer, DeployerArgs};
+use actors::{RestartPolicy, Source, Supervisor, SupervisorArgs};
+use anyhow::Context;
 use clap::{Parser, Subcommand};
-use cron::{CronRunner, CronRunnerArgs};
+use config::{
+    Acme, Canary, Challenge, Config, CronTarget, DeployTarget, Drain, Edge, KeepPrevious, Route,
+    Tls,
+};
+use git::{Auth, DivergePolicy};
 use ractor::Actor;
-use std::path::PathBuf;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
 use std::time::Duration;
 
 #[derive(Parser)]
@@ -18,9 +32,34 @@ use std::time::Duration;
 struct Cli {
     #[command(subcommand)]
     command: Commands,
+
+    /// Restarts allowed per target within the restart window before giving up
+    #[arg(long, global = true, default_value = "5")]
+    max_restarts: usize,
+
+    /// Restart window in seconds
+    #[arg(long, global = true, default_value = "600")]
+    restart_window: u64,
+
+    /// Serve the control API on this address (e.g. 127.0.0.1:7070)
+    #[arg(long, global = true, conflicts_with = "api_socket")]
+    api: Option<SocketAddr>,
+
+    /// Serve the control API on this unix socket
+    #[arg(long, global = true)]
+    api_socket: Option<PathBuf>,
+
+    /// Accept GitHub, GitLab and Gitea push webhooks on this address (e.g. 0.0.0.0:9000)
+    #[arg(long, global = true, requires = "webhook_secret_file")]
+    webhook: Option<SocketAddr>,
+
+    /// File holding the shared webhook secret
+    #[arg(long, global = true)]
+    webhook_secret_file: Option<PathBuf>,
 }
 
 #[derive(Subcommand)]
+#[allow(clippy::large_enum_variant)]
 enum Commands {
     /// Deploy a docker-compose app with blue-green strategy
     Deploy {
@@ -32,14 +71,54 @@ enum Commands {
         #[arg(long, default_value = "main")]
         branch: String,
 
+        /// Deploy the highest version tag matching this glob (e.g. "v*") instead of the branch tip
+        #[arg(long, conflicts_with = "commit")]
+        tag: Option<String>,
+
+        /// Deploy exactly this commit instead of the branch tip
+        #[arg(long)]
+        commit: Option<String>,
+
+        /// What to do when the branch was force-pushed or the worktree is dirty
+        #[arg(long, value_enum, default_value = "halt")]
+        on_diverge: DivergePolicy,
+
+        #[command(flatten)]
+        auth: Auth,
+
+        /// Init and update git submodules
+        #[arg(long)]
+        submodules: bool,
+
+        /// Only check out this directory and only redeploy on changes under it
+        #[arg(long)]
+        subdir: Option<String>,
+
+        /// Only redeploy when a changed file matches this glob (repeatable)
+        #[arg(long)]
+        include: Vec<String>,
+
+        /// Ignore changed files matching this glob (repeatable)
+        #[arg(long)]
+        exclude: Vec<String>,
+
         /// Docker compose file path (relative to repo root)
         #[arg(long, default_value = "docker-compose.yml")]
         compose: String,
 
+        /// Run this compose service once in a shared project instead of in
+        /// each slot, e.g. a database (repeatable)
+        #[arg(long)]
+        shared: Vec<String>,
+
         /// Port to expose the app on
         #[arg(long)]
         port: u16,
 
+        /// Image of the app's Traefik container; changing it recreates the container
+        #[arg(long, default_value_t = config::default_traefik_image())]
+        traefik_image: String,
+
         /// Poll interval in seconds
         #[arg(long, default_value = "60")]
         interval: u64,
@@ -48,6 +127,104 @@ enum Commands {
         #[arg(long, default_value = "120")]
         health_timeout: u64,
 
+        /// Readiness probe the new slot must pass: http:<service>:<port>/<path>,
+        /// tcp:<service>:<port> or exec:<service>:<command> (repeatable)
+        #[arg(long)]
+        probe: Vec<probe::Probe>,
+
+        /// Seconds to watch the new slot after the switch, bringing the old
+        /// slot back if it degrades
+        #[arg(long, default_value = "0")]
+        bake: u64,
+
+        /// Route traffic to this service's port in the active slot instead of
+        /// relying on compose labels: <service>:<port>
+        #[arg(long, value_parser = parse_service_port)]
+        route: Option<(String, u16)>,
+
+        /// Shift traffic to the new slot in steps instead of all at once
+        #[arg(long, requires = "route")]
+        canary: bool,
+
+        /// Percent of traffic sent to the new slot at each canary step
+        #[arg(
+            long,
+            value_delimiter = ',',
+            default_value = "5,25,50,100",
+            requires = "canary"
+        )]
+        canary_steps: Vec<u8>,
+
+        /// Seconds to watch the new slot at each canary step
+        #[arg(long, default_value = "60", requires = "canary")]
+        canary_step_duration: u64,
+
+        /// Get a certificate through ACME and serve the app over HTTPS
+        #[arg(long, requires = "route", conflicts_with = "tls_cert")]
+        acme: bool,
+
+        /// ACME account contact
+        #[arg(long, requires = "acme")]
+        acme_email: Option<String>,
+
+        /// How the CA verifies the domains
+        #[arg(long, value_enum, default_value = "http", requires = "acme")]
+        acme_challenge: Challenge,
+
+        /// ACME directory URL, e.g. a staging CA or Pebble
+        #[arg(long, requires = "acme")]
+        acme_ca_server: Option<String>,
+
+        /// PEM bundle to trust for the ACME directory
+        #[arg(long, requires = "acme_ca_server")]
+        acme_ca_cert: Option<PathBuf>,
+
+        /// Serve the app over HTTPS with this PEM certificate chain
+        #[arg(long, requires_all = ["route", "tls_key"])]
+        tls_cert: Option<PathBuf>,
+
+        /// Private key of --tls-cert
+        #[arg(long, requires = "tls_cert")]
+        tls_key: Option<PathBuf>,
+
+        /// Domain the certificate covers (repeatable)
+        #[arg(long)]
+        tls_domain: Vec<String>,
+
+        /// Keep serving plain HTTP instead of redirecting it to HTTPS
+        #[arg(long)]
+        no_https_redirect: bool,
+
+        /// Host port of HTTPS on the app's own Traefik
+        #[arg(long, default_value_t = config::default_https_port())]
+        https_port: u16,
+
+        /// Seconds the old slot gets to finish in-flight requests before
+        /// it is stopped
+        #[arg(long, requires = "route")]
+        drain: Option<u64>,
+
+        /// Signal asking the old slot's containers to shut down after draining
+        #[arg(long, default_value_t = config::default_stop_signal(), requires = "drain")]
+        stop_signal: String,
+
+        /// Seconds between the stop signal and SIGKILL
+        #[arg(long, default_value_t = config::default_stop_grace(), requires = "drain")]
+        stop_grace: u64,
+
+        /// What happens to the old slot after a successful switch; a kept
+        /// slot makes rolling back a matter of starting it and routing to it
+        #[arg(long, value_enum, default_value = "down")]
+        keep_previous: KeepPrevious,
+
+        /// Seconds a kept old slot stays around before it is removed
+        #[arg(long, default_value_t = config::default_retention())]
+        retention: u64,
+
+        /// Number of deploy history entries to keep
+        #[arg(long, default_value = "100")]
+        history_limit: usize,
+
         /// Local directory to clone repo into
         #[arg(long)]
         dir: Option<PathBuf>,
@@ -63,6 +240,22 @@ enum Commands {
         #[arg(long, default_value = "main")]
         branch: String,
 
+        /// What to do when the branch was force-pushed or the worktree is dirty
+        #[arg(long, value_enum, default_value = "halt")]
+        on_diverge: DivergePolicy,
+
+        #[command(flatten)]
+        auth: Auth,
+
+        /// Init and update git submodules
+        #[arg(long)]
+        submodules: bool,
+
+        /// Only check out this directory, plus the repository root holding
+        /// rollploy.cron.yml
+        #[arg(long)]
+        subdir: Option<String>,
+
         /// Git pull interval in seconds
         #[arg(long, default_value = "60")]
         interval: u64,
@@ -71,6 +264,48 @@ enum Commands {
         #[arg(long)]
         dir: Option<PathBuf>,
     },
+
+    /// Run every deploy and cron target listed in a config file
+    Serve {
+        /// Path to the config file
+        #[arg(long, default_value = "rollploy.yml")]
+        config: PathBuf,
+
+        /// Config reload check interval in seconds
+        #[arg(long, default_value = "5")]
+        reload_interval: u64,
+    },
+
+    /// Show the deploy history of an app
+    History {
+        /// Git repository URL, used to find the default clone directory
+        #[arg(long, required_unless_present = "dir")]
+        repo: Option<String>,
+
+        /// Local directory the repo was cloned into
+        #[arg(long)]
+        dir: Option<PathBuf>,
+
+        /// Number of most recent entries to show
+        #[arg(long, default_value = "20")]
+        limit: usize,
+
+        /// Print entries as JSON lines
+        #[arg(long)]
+        json: bool,
+    },
+
+    /// Roll a running deployer back to its previous commit (needs --api or --api-socket)
+    Rollback {
+        /// App name of the deployer
+        app: String,
+    },
+
+    /// Cancel a running deployer's in-flight rollout (needs --api or --api-socket)
+    Abort {
+        /// App name of the deployer
+        app: String,
+    },
 }
 
 #[tokio::main]
@@ -79,62 +314,260 @@ async fn main() -> anyhow::Result<()> {
 
     let cli = Cli::parse();
 
-    match cli.command {
+    match &cli.command {
+        Commands::Rollback { app } => return control(&cli, app, "rollback").await,
+        Commands::Abort { app } => return control(&cli, app, "abort").await,
+        _ => {}
+    }
+
+    if let Commands::History {
+        repo,
+        dir,
+        limit,
+        json,
+    } = &cli.command
+    {
+        let dir = match (dir, repo) {
+            (Some(dir), _) => dir.clone(),
+            (None, Some(repo)) => config::derive_local_path(&config::repo_name(repo)),
+            (None, None) => unreachable!("clap requires --repo or --dir"),
+        };
+        return print_history(&dir, *limit, *json);
+    }
+
+    if let Some(addr) = cli.api {
+        api::start(api::Listen::Tcp(addr)).await?;
+    } else if let Some(path) = cli.api_socket.clone() {
+        api::start(api::Listen::Unix(path)).await?;
+    }
+
+    if let (Some(addr), Some(path)) = (cli.webhook, &cli.webhook_secret_file) {
+        let secret = std::fs::read_to_string(path)
+            .with_context(|| format!("failed to read {}", path.display()))?
+            .trim()
+            .to_string();
+        if secret.is_empty() {
+            anyhow::bail!("webhook secret file {} is empty", path.display());
+        }
+        webhook::start(addr, secret).await?;
+    }
+
+    let restart = RestartPolicy {
+        max_restarts: cli.max_restarts,
+        window: Duration::from_secs(cli.restart_window),
+    };
+
+    let source = match cli.command {
         Commands::Deploy {
             repo,
             branch,
+            tag,
+            commit,
+            on_diverge,
+            auth,
+            submodules,
+            subdir,
+            include,
+            exclude,
             compose,
+            shared,
             port,
+            traefik_image,
             interval,
             health_timeout,
+            probe,
+            bake,
+            route,
+            canary,
+            canary_steps,
+            canary_step_duration,
+            acme,
+            acme_email,
+            acme_challenge,
+            acme_ca_server,
+            acme_ca_cert,
+            tls_cert,
+            tls_key,
+            tls_domain,
+            no_https_redirect,
+            https_port,
+            drain,
+            stop_signal,
+            stop_grace,
+            keep_previous,
+            retention,
+            history_limit,
             dir,
-        } => {
-            let local_path = dir.unwrap_or_else(|| derive_local_path(&repo));
-
-            let args = DeployerArgs {
-                repo_url: repo,
+        } => Source::Static(Config {
+            deploys: vec![DeployTarget {
+                name: None,
+                repo,
                 branch,
-                local_path,
-                compose_file: compose,
-                port,
-                interval: Duration::from_secs(interval),
-                health_timeout: Duration::from_secs(health_timeout),
-            };
-
-            let (_actor, handle) =
-                Actor::spawn(Some("deployer".to_string()), Deployer, args).await?;
-            handle.await?;
-        }
+                tag,
+                commit,
+                on_diverge,
+                auth,
+                submodules,
+                subdir,
+                include,
+                exclude,
+                compose,
+                shared,
+                port: Some(port),
+                host: None,
+                path_prefix: None,
+                traefik_image,
+                interval,
+                health_timeout,
+                probes: probe,
+                bake,
+                route: route.map(|(service, port)| Route { service, port }),
+                canary: canary.then_some(Canary {
+                    steps: canary_steps,
+                    step_duration: canary_step_duration,
+                }),
+                tls: (acme || tls_cert.is_some()).then(|| Tls {
+                    domains: tls_domain,
+                    acme: acme.then_some(Acme {
+                        email: acme_email,
+                        challenge: acme_challenge,
+                        ca_server: acme_ca_server,
+                        ca_cert: acme_ca_cert,
+                    }),
+                    cert: tls_cert,
+                    key: tls_key,
+                    redirect: !no_https_redirect,
+                    port: https_port,
+                }),
+                drain: drain.map(|timeout| Drain {
+                    timeout,
+                    stop_signal,
+                    stop_grace,
+                }),
+                keep_previous,
+                retention,
+                history_limit,
+                dir,
+            }],
+            crons: Vec::new(),
+            // The edge proxy is shared between apps, so only `serve` runs it
+            edge: Edge::default(),
+        }),
 
         Commands::Cron {
             repo,
             branch,
+            on_diverge,
+            auth,
+            submodules,
+            subdir,
             interval,
             dir,
-        } => {
-            let local_path = dir.unwrap_or_else(|| derive_local_path(&repo));
-
-            let args = CronRunnerArgs {
-                repo_url: repo,
+        } => Source::Static(Config {
+            deploys: Vec::new(),
+            crons: vec![CronTarget {
+                name: None,
+                repo,
                 branch,
-                local_path,
-                check_interval: Duration::from_secs(interval),
-            };
+                on_diverge,
+                auth,
+                submodules,
+                subdir,
+                interval,
+                dir,
+            }],
+            edge: Edge::default(),
+        }),
+
+        Commands::Serve {
+            config,
+            reload_interval,
+        } => Source::File {
+            path: config,
+            reload_interval: Duration::from_secs(reload_interval),
+        },
+
+        Commands::Rollback { .. } | Commands::Abort { .. } | Commands::History { .. } => {
+            unreachable!("handled above")
+        }
+    };
+    if let Source::Static(config) = &source {
+        config::validate(config)?;
+    }
 
-            let (_actor, handle) =
-                Actor::spawn(Some("cron-runner".to_string()), CronRunner, args).await?;
-            handle.await?;
+    let (failed, failure) = tokio::sync::oneshot::channel();
+    let args = SupervisorArgs {
+        source,
+        restart,
+        failed,
+    };
+    let (_actor, handle) = Actor::spawn(Some("supervisor".to_string()), Supervisor, args).await?;
+    handle.await?;
+
+    // Exit non-zero so a service manager restarts or flags us
+    if let Ok(reason) = failure.await {
+        anyhow::bail!("supervisor stopped: {}", reason);
+    }
+    Ok(())
+}
+
+/// Ask a running rollploy to perform `action` on `app` through its control API
+async fn control(cli: &Cli, app: &str, action: &str) -> anyhow::Result<()> {
+    let target = match (cli.api, &cli.api_socket) {
+        (Some(addr), _) => http::Target::Tcp(addr),
+        (None, Some(path)) => http::Target::Unix(path.clone()),
+        (None, None) => anyhow::bail!("pass --api or --api-socket of the running rollploy"),
+    };
+
+    let path = format!("/apps/{}/{}", app, action);
+    let response = http::request(&target, "POST", &path).await?;
+    if response.status != 202 {
+        anyhow::bail!("{} failed ({}): {}", action, response.status, response.body);
+    }
+    println!("{} of {} queued", action, app);
+    Ok(())
+}
+
+fn print_history(dir: &Path, limit: usize, json: bool) -> anyhow::Result<()> {
+    let entries = history::load(dir)?;
+    let entries = &entries[entries.len().saturating_sub(limit)..];
+
+    if json {
+        for entry in entries {
+            println!("{}", serde_json::to_string(entry)?);
         }
+        return Ok(());
     }
 
+    println!(
+        "{:<20}  {:>8}  {:<8}  {:<5}  {:<10}  {:<9}  ERROR",
+        "STARTED", "DURATION", "KIND", "SLOT", "COMMIT", "OUTCOME"
+    );
+    for entry in entries {
+        let duration = (entry.finished_at - entry.started_at).num_seconds();
+        let sha = entry.sha.as_deref().unwrap_or("-");
+        println!(
+            "{:<20}  {:>7}s  {:<8}  {:<5}  {:<10}  {:<9}  {}",
+            entry.started_at.format("%Y-%m-%d %H:%M:%S"),
+            duration,
+            entry.kind.as_str(),
+            entry.slot.as_str(),
+            &sha[..sha.len().min(10)],
+            entry.outcome.as_str(),
+            entry
+                .error
+                .as_deref()
+                .and_then(|e| e.lines().next())
+                .unwrap_or("")
+        );
+    }
     Ok(())
 }
 
-fn derive_local_path(repo: &str) -> PathBuf {
-    let repo_name = repo.split('/').last().unwrap_or("repo");
-    let repo_name = repo_name.trim_end_matches(".git");
-    dirs::state_dir()
-        .unwrap_or_else(|| PathBuf::from("/var/lib"))
-        .join("rollploy")
-        .join(repo_name)
+fn parse_service_port(s: &str) -> Result<(String, u16), String> {
+    let (service, port) = s
+        .rsplit_once(':')
+        .ok_or_else(|| format!("expected <service>:<port>, got {}", s))?;
+    let port = port.parse().map_err(|_| format!("invalid port in {}", s))?;
+    Ok((service.to_string(), port))
 }
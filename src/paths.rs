@@ -0,0 +1,41 @@
+use anyhow::{Context, Result};
+use globset::{Glob, GlobSet, GlobSetBuilder};
+
+/// Decides whether changed files warrant a rollout
+#[derive(Debug, Clone)]
+pub struct PathFilter {
+    include: Option<GlobSet>,
+    exclude: GlobSet,
+}
+
+impl PathFilter {
+    /// An empty include list matches every file
+    pub fn new(include: &[String], exclude: &[String]) -> Result<Self> {
+        Ok(Self {
+            include: if include.is_empty() {
+                None
+            } else {
+                Some(build(include)?)
+            },
+            exclude: build(exclude)?,
+        })
+    }
+
+    /// Files that are included and not excluded
+    pub fn relevant<'a>(&self, files: &'a [String]) -> Vec<&'a str> {
+        files
+            .iter()
+            .map(String::as_str)
+            .filter(|f| self.include.as_ref().is_none_or(|set| set.is_match(f)))
+            .filter(|f| !self.exclude.is_match(f))
+            .collect()
+    }
+}
+
+fn build(patterns: &[String]) -> Result<GlobSet> {
+    let mut builder = GlobSetBuilder::new();
+    for pattern in patterns {
+        builder.add(Glob::new(pattern).with_context(|| format!("invalid glob {}", pattern))?);
+    }
+    Ok(builder.build()?)
+}
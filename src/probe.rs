@@ -0,0 +1,251 @@
+use crate::{docker, http};
+use anyhow::{anyhow, bail, Result};
+use serde::Deserialize;
+use std::fmt;
+use std::net::SocketAddr;
+use std::str::FromStr;
+use std::time::Duration;
+use tokio::task::JoinSet;
+use tokio::time::{sleep, timeout, Instant};
+use tracing::{debug, info};
+
+/// A readiness check the new slot has to pass before traffic switches to it
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct Probe {
+    #[serde(flatten)]
+    pub check: Check,
+    /// Seconds between attempts
+    #[serde(default = "default_interval")]
+    pub interval: u64,
+    /// Consecutive successes needed to pass
+    #[serde(default = "default_success_threshold")]
+    pub success_threshold: u32,
+    /// Consecutive failures that mark a live slot as degraded during the bake
+    #[serde(default = "default_failure_threshold")]
+    pub failure_threshold: u32,
+    /// Seconds before a single attempt counts as failed
+    #[serde(default = "default_timeout")]
+    pub timeout: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Check {
+    /// GET a path on each container of the service over the app network
+    Http {
+        service: String,
+        port: u16,
+        #[serde(default = "default_path")]
+        path: String,
+        /// Accepted status codes; any 2xx or 3xx if empty
+        #[serde(default)]
+        status: Vec<u16>,
+    },
+    /// Open a TCP connection to each container of the service
+    Tcp { service: String, port: u16 },
+    /// Run a command in each container of the service with `docker exec`
+    Exec {
+        service: String,
+        command: Vec<String>,
+    },
+}
+
+fn default_interval() -> u64 {
+    2
+}
+
+fn default_success_threshold() -> u32 {
+    1
+}
+
+fn default_failure_threshold() -> u32 {
+    3
+}
+
+fn default_timeout() -> u64 {
+    5
+}
+
+fn default_path() -> String {
+    "/".to_string()
+}
+
+impl Check {
+    fn service(&self) -> &str {
+        match self {
+            Check::Http { service, .. }
+            | Check::Tcp { service, .. }
+            | Check::Exec { service, .. } => service,
+        }
+    }
+}
+
+impl fmt::Display for Probe {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.check {
+            Check::Http {
+                service,
+                port,
+                path,
+                ..
+            } => write!(f, "http:{}:{}{}", service, port, path),
+            Check::Tcp { service, port } => write!(f, "tcp:{}:{}", service, port),
+            Check::Exec { service, command } => write!(f, "exec:{}:{}", service, command.join(" ")),
+        }
+    }
+}
+
+/// Short form for the command line: `http:web:8080/healthz`, `tcp:db:5432`
+/// or `exec:web:./healthcheck --quick`, with default timings
+impl FromStr for Probe {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (kind, rest) = s
+            .split_once(':')
+            .ok_or_else(|| format!("expected <kind>:<service>:..., got {}", s))?;
+        let (service, target) = rest
+            .split_once(':')
+            .ok_or_else(|| format!("expected <kind>:<service>:..., got {}", s))?;
+        let service = service.to_string();
+        let port = |p: &str| {
+            p.parse::<u16>()
+                .map_err(|_| format!("invalid port in {}", s))
+        };
+
+        let check = match kind {
+            "http" => {
+                let (port_str, path) = match target.find('/') {
+                    Some(i) => (&target[..i], &target[i..]),
+                    None => (target, "/"),
+                };
+                Check::Http {
+                    service,
+                    port: port(port_str)?,
+                    path: path.to_string(),
+                    status: Vec::new(),
+                }
+            }
+            "tcp" => Check::Tcp {
+                service,
+                port: port(target)?,
+            },
+            "exec" => Check::Exec {
+                service,
+                command: target.split_whitespace().map(String::from).collect(),
+            },
+            _ => return Err(format!("unknown probe kind {} (http, tcp or exec)", kind)),
+        };
+
+        Ok(Probe {
+            check,
+            interval: default_interval(),
+            success_threshold: default_success_threshold(),
+            failure_threshold: default_failure_threshold(),
+            timeout: default_timeout(),
+        })
+    }
+}
+
+/// Run all probes against `project` concurrently until each has passed its
+/// success threshold, failing once `limit` has elapsed
+pub async fn wait_ready(
+    project: &str,
+    network: &str,
+    probes: &[Probe],
+    limit: Duration,
+) -> Result<()> {
+    let deadline = Instant::now() + limit;
+    // Dropping the set aborts probes that are still running
+    let mut running = JoinSet::new();
+    for probe in probes {
+        let (project, network, probe) = (project.to_string(), network.to_string(), probe.clone());
+        running.spawn(async move { wait_probe(&project, &network, &probe, deadline).await });
+    }
+    while let Some(result) = running.join_next().await {
+        result??;
+    }
+    Ok(())
+}
+
+async fn wait_probe(project: &str, network: &str, probe: &Probe, deadline: Instant) -> Result<()> {
+    let interval = Duration::from_secs(probe.interval);
+    let mut passes = 0;
+    let mut last_error = None;
+    loop {
+        match attempt(project, network, probe).await {
+            Ok(()) => {
+                passes += 1;
+                if passes >= probe.success_threshold {
+                    info!(probe = %probe, project = %project, "probe passed");
+                    return Ok(());
+                }
+            }
+            Err(e) => {
+                debug!(probe = %probe, error = %e, "probe failed, retrying");
+                passes = 0;
+                last_error = Some(e);
+            }
+        }
+
+        if Instant::now() + interval > deadline {
+            match last_error.filter(|_| passes == 0) {
+                Some(e) => bail!("probe {} did not pass: {:#}", probe, e),
+                None => bail!(
+                    "probe {} passed only {} of {} times in a row",
+                    probe,
+                    passes,
+                    probe.success_threshold
+                ),
+            }
+        }
+        sleep(interval).await;
+    }
+}
+
+/// Run a probe once, giving up after its timeout
+pub async fn attempt(project: &str, network: &str, probe: &Probe) -> Result<()> {
+    timeout(
+        Duration::from_secs(probe.timeout),
+        check(project, network, &probe.check),
+    )
+    .await
+    .unwrap_or_else(|_| Err(anyhow!("timed out after {}s", probe.timeout)))
+}
+
+/// One attempt against every container of the probed service
+async fn check(project: &str, network: &str, check: &Check) -> Result<()> {
+    let containers = docker::service_containers(project, check.service()).await?;
+    if containers.is_empty() {
+        bail!("no running container for service {}", check.service());
+    }
+
+    for id in &containers {
+        match check {
+            Check::Http {
+                port, path, status, ..
+            } => {
+                let addr = SocketAddr::new(docker::container_ip(id, network).await?, *port);
+                let response = http::request(&http::Target::Tcp(addr), "GET", path).await?;
+                let ok = if status.is_empty() {
+                    (200..400).contains(&response.status)
+                } else {
+                    status.contains(&response.status)
+                };
+                if !ok {
+                    bail!("GET {} on {} returned {}", path, addr, response.status);
+                }
+            }
+            Check::Tcp { port, .. } => {
+                let addr = SocketAddr::new(docker::container_ip(id, network).await?, *port);
+                tokio::net::TcpStream::connect(addr)
+                    .await
+                    .map_err(|e| anyhow!("connect to {} failed: {}", addr, e))?;
+            }
+            Check::Exec { command, .. } => {
+                docker::exec(id, command).await?;
+            }
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,99 @@
+use std::process::Stdio;
+use std::time::Duration;
+use tokio::process::Command;
+
+/// Keep error messages readable when a tool dumps a lot of output
+const MAX_STDERR: usize = 4000;
+
+#[derive(Debug)]
+pub struct Output {
+    pub stdout: String,
+    pub stderr: String,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum CommandError {
+    #[error("failed to run `{command}`: {source}")]
+    Spawn {
+        command: String,
+        source: std::io::Error,
+    },
+    #[error("`{command}` timed out after {}s", timeout.as_secs())]
+    Timeout { command: String, timeout: Duration },
+    #[error("`{command}` exited with {}: {stderr}", code.map_or("signal".to_string(), |c| c.to_string()))]
+    Failed {
+        command: String,
+        code: Option<i32>,
+        stderr: String,
+    },
+}
+
+impl CommandError {
+    /// Exit code of a command that ran to completion and failed
+    pub fn code(&self) -> Option<i32> {
+        match self {
+            CommandError::Failed { code, .. } => *code,
+            _ => None,
+        }
+    }
+}
+
+/// Run `cmd` to completion, capturing its output. Non-zero exits become
+/// [`CommandError::Failed`] carrying stderr; the process is killed after
+/// `timeout`, or as soon as the returned future is dropped.
+pub async fn run(cmd: &mut Command, timeout: Duration) -> Result<Output, CommandError> {
+    let command = describe(cmd);
+
+    let child = cmd
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()
+        .map_err(|source| CommandError::Spawn {
+            command: command.clone(),
+            source,
+        })?;
+
+    // Both pipes are drained concurrently, so a chatty process can't block on a full pipe
+    let output = match tokio::time::timeout(timeout, child.wait_with_output()).await {
+        Ok(Ok(output)) => output,
+        Ok(Err(source)) => return Err(CommandError::Spawn { command, source }),
+        Err(_) => return Err(CommandError::Timeout { command, timeout }),
+    };
+    let status = output.status;
+    let output = Output {
+        stdout: String::from_utf8_lossy(&output.stdout).into_owned(),
+        stderr: String::from_utf8_lossy(&output.stderr).into_owned(),
+    };
+
+    if !status.success() {
+        return Err(CommandError::Failed {
+            command,
+            code: status.code(),
+            stderr: tail(output.stderr.trim(), MAX_STDERR).to_string(),
+        });
+    }
+
+    Ok(output)
+}
+
+fn describe(cmd: &Command) -> String {
+    let cmd = cmd.as_std();
+    std::iter::once(cmd.get_program())
+        .chain(cmd.get_args())
+        .map(|s| s.to_string_lossy())
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+fn tail(s: &str, max: usize) -> &str {
+    if s.len() <= max {
+        return s;
+    }
+    let mut start = s.len() - max;
+    while !s.is_char_boundary(start) {
+        start += 1;
+    }
+    &s[start..]
+}
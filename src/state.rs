@@ -1,4 +1,5 @@
 use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
 use std::path::Path;
 
@@ -29,6 +30,15 @@ impl Slot {
 #[derive(Debug, Clone, Serialize, Deserialize, Default)]
 pub struct PersistedState {
     pub active_slot: Slot,
+    /// Commit running in the active slot
+    #[serde(default)]
+    pub deployed_sha: Option<String>,
+    /// Commit that was live before `deployed_sha`, used for rollback
+    #[serde(default)]
+    pub previous_sha: Option<String>,
+    /// Until when the inactive slot keeps `previous_sha` for a fast rollback
+    #[serde(default)]
+    pub warm_until: Option<DateTime<Utc>>,
 }
 
 const STATE_FILE: &str = ".rollploy-state.json";
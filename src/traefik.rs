@@ -1,45 +1,735 @@
-use anyhow::{bail, Context, Result};
-use std::process::Command;
+use crate::config::{Acme, Challenge, Tls};
+use crate::process::CommandError;
+use crate::{docker, http};
+use anyhow::{anyhow, bail, Context, Result};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::collections::{BTreeMap, HashMap};
+use std::io::Write;
+use std::net::SocketAddr;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tokio::time::{sleep, timeout, Instant};
+use tracing::info;
 
-pub fn start(app_name: &str, port: u16, network: &str) -> Result<()> {
-    let container_name = format!("rollploy-{}-traefik", app_name);
+/// Directory under the app's checkout holding Traefik's dynamic config
+pub const DYNAMIC_DIR: &str = ".rollploy-traefik";
+/// Where the dynamic config directory is mounted in the Traefik container
+const CONTAINER_DYNAMIC_DIR: &str = "/etc/traefik/dynamic";
+const ROUTES_FILE: &str = "routes.yml";
+/// Above the rule-length priority of any router defined through labels
+const ROUTE_PRIORITY: u32 = 1_000_000;
+/// Port of Traefik's API inside its container
+const API_PORT: u16 = 8080;
+/// How long Traefik gets to pick up a changed routes file
+const VERIFY_TIMEOUT: Duration = Duration::from_secs(15);
+/// How long one request to Traefik's API may take
+const API_TIMEOUT: Duration = Duration::from_secs(5);
+/// The Traefik container shared by every app in edge mode
+const EDGE_CONTAINER: &str = "rollploy-edge";
+/// Where ACME accounts and certificates are kept in the container
+const CONTAINER_ACME_DIR: &str = "/etc/traefik/acme";
+/// Where extra ACME CA certificates are mounted in the container
+const CONTAINER_CA_DIR: &str = "/etc/traefik/ca";
 
-    // Check if already running (idempotent)
-    let output = Command::new("docker")
-        .args(["ps", "-q", "-f", &format!("name={}", container_name)])
-        .output()
-        .context("docker ps failed")?;
+/// Where an app's traffic comes in
+#[derive(Debug, Clone, PartialEq)]
+pub enum Entry {
+    /// The app's own Traefik container, published on this host port
+    Port(u16),
+    /// The shared edge proxy on ports 80 and 443, matching requests by host
+    /// and/or path prefix
+    Edge {
+        host: Option<String>,
+        path_prefix: Option<String>,
+    },
+}
+
+impl std::fmt::Display for Entry {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Entry::Port(port) => write!(f, "port {}", port),
+            Entry::Edge { host, path_prefix } => write!(
+                f,
+                "edge {}{}",
+                host.as_deref().unwrap_or("*"),
+                path_prefix.as_deref().unwrap_or("/")
+            ),
+        }
+    }
+}
+
+/// Create the app's Traefik container, start it if it was stopped, or
+/// recreate it if its image, published port, mounts or flags differ from
+/// what this version of rollploy wants
+pub async fn reconcile(
+    app_name: &str,
+    image: &str,
+    port: u16,
+    network: &str,
+    dynamic_dir: &Path,
+    tls: Option<&Tls>,
+) -> Result<()> {
+    std::fs::create_dir_all(dynamic_dir).context("failed to create traefik config directory")?;
+    let mut spec = Spec {
+        name: container_name(app_name),
+        image: image.to_string(),
+        network: network.to_string(),
+        ports: vec![(port, 80)],
+        binds: vec![
+            "/var/run/docker.sock:/var/run/docker.sock:ro".to_string(),
+            format!(
+                "{}:{}:ro",
+                std::path::absolute(dynamic_dir)?.display(),
+                CONTAINER_DYNAMIC_DIR
+            ),
+        ],
+        args: vec![
+            "--providers.docker=true".to_string(),
+            "--providers.docker.exposedbydefault=false".to_string(),
+            format!("--providers.docker.network={}", network),
+            format!("--providers.file.directory={}", CONTAINER_DYNAMIC_DIR),
+            "--providers.file.watch=true".to_string(),
+            "--entrypoints.web.address=:80".to_string(),
+            // Not published; rollploy reaches it over the app network
+            "--api.insecure=true".to_string(),
+        ],
+        env: Vec::new(),
+    };
+    if let Some(tls) = tls {
+        spec.ports.push((tls.port, 443));
+        spec.args
+            .push("--entrypoints.websecure.address=:443".to_string());
+        if let Some(acme) = &tls.acme {
+            spec.add_resolver(app_name, &format!("{}-acme", spec.name), acme)?;
+        }
+    }
+    ensure(&spec).await
+}
+
+/// Bring the shared edge proxy in line like [`reconcile`] and attach it to
+/// the network of every app in `apps`. Apps with ACME get a certificate
+/// resolver each.
+pub async fn reconcile_edge(image: &str, apps: &[(String, Option<Tls>)]) -> Result<()> {
+    let dir = edge_dir();
+    std::fs::create_dir_all(&dir).context("failed to create edge config directory")?;
+    let mut spec = Spec {
+        name: EDGE_CONTAINER.to_string(),
+        image: image.to_string(),
+        network: "bridge".to_string(),
+        ports: vec![(80, 80), (443, 443)],
+        binds: vec![format!("{}:{}:ro", dir.display(), CONTAINER_DYNAMIC_DIR)],
+        args: vec![
+            format!("--providers.file.directory={}", CONTAINER_DYNAMIC_DIR),
+            "--providers.file.watch=true".to_string(),
+            "--entrypoints.web.address=:80".to_string(),
+            "--entrypoints.websecure.address=:443".to_string(),
+            // Not published; rollploy reaches it over the app networks
+            "--api.insecure=true".to_string(),
+        ],
+        env: Vec::new(),
+    };
+    for (app, tls) in apps {
+        if let Some(acme) = tls.as_ref().and_then(|tls| tls.acme.as_ref()) {
+            spec.add_resolver(app, &format!("{}-acme", EDGE_CONTAINER), acme)?;
+        }
+    }
+    ensure(&spec).await?;
+
+    // A recreated container only keeps the network it was started on
+    for (app, _) in apps {
+        let network = docker::network_name(app);
+        docker::create_network(&network).await?;
+        docker::connect(&network, EDGE_CONTAINER).await?;
+    }
+    Ok(())
+}
 
-    if !output.stdout.is_empty() {
+/// Drop the edge routes of apps other than `apps`, e.g. ones removed from
+/// the config or moved back to their own Traefik
+pub fn prune_edge(apps: &[String]) -> Result<()> {
+    let dir = edge_dir();
+    if !dir.exists() {
         return Ok(());
     }
+    for entry in std::fs::read_dir(&dir).context("failed to read edge config directory")? {
+        let path = entry?.path();
+        let Some(app) = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .and_then(|name| name.strip_suffix(".yml"))
+        else {
+            continue;
+        };
+        if !apps.iter().any(|a| a == app) {
+            info!(app = %app, "removing edge routes of app no longer on the edge");
+            std::fs::remove_file(&path).context("failed to remove edge routes")?;
+        }
+    }
+    Ok(())
+}
 
-    let status = Command::new("docker")
-        .args([
-            "run",
-            "-d",
-            "--name",
-            &container_name,
-            "--network",
-            network,
-            "-p",
-            &format!("{}:80", port),
-            "-v",
-            "/var/run/docker.sock:/var/run/docker.sock:ro",
-            "--restart",
-            "unless-stopped",
-            "traefik:v3.0",
-            "--providers.docker=true",
-            "--providers.docker.exposedbydefault=false",
-            &format!("--providers.docker.network={}", network),
-            "--entrypoints.web.address=:80",
-        ])
-        .status()
-        .context("failed to start traefik")?;
+/// Attach the shared edge proxy to an app's network
+pub async fn join_edge(network: &str) -> Result<()> {
+    docker::connect(network, EDGE_CONTAINER)
+        .await
+        .context("failed to attach the edge proxy, is it running?")
+}
 
-    if !status.success() {
-        bail!("failed to start traefik container");
+/// Remove the app's own Traefik container, freeing its port once the app
+/// moved to the edge proxy
+pub async fn remove(app_name: &str) -> Result<()> {
+    let name = container_name(app_name);
+    if inspect(&name).await?.is_some() {
+        info!(container = %name, "removing traefik of app now on the edge");
+        docker::docker(&["rm", "-f", &name])
+            .await
+            .context("failed to remove traefik")?;
     }
+    Ok(())
+}
 
+/// Create, recreate or start the container described by `spec`
+async fn ensure(spec: &Spec) -> Result<()> {
+    let Some(current) = inspect(&spec.name).await? else {
+        info!(container = %spec.name, image = %spec.image, "starting traefik");
+        return run(spec).await;
+    };
+    if let Some(change) = spec.diff(&current) {
+        info!(container = %spec.name, change = %change, "traefik config changed, recreating");
+        docker::docker(&["rm", "-f", &spec.name])
+            .await
+            .context("failed to remove traefik")?;
+        return run(spec).await;
+    }
+    if !current.state.running {
+        info!(container = %spec.name, "starting stopped traefik");
+        docker::docker(&["start", &spec.name])
+            .await
+            .context("failed to start traefik")?;
+    }
     Ok(())
 }
+
+/// How a Traefik container should look
+struct Spec {
+    name: String,
+    image: String,
+    network: String,
+    /// Published (host, container) ports
+    ports: Vec<(u16, u16)>,
+    binds: Vec<String>,
+    args: Vec<String>,
+    env: Vec<String>,
+}
+
+impl Spec {
+    /// Add an ACME certificate resolver named `resolver`, keeping its
+    /// account and certificates in the docker volume `volume`
+    fn add_resolver(&mut self, resolver: &str, volume: &str, acme: &Acme) -> Result<()> {
+        let bind = format!("{}:{}", volume, CONTAINER_ACME_DIR);
+        if !self.binds.contains(&bind) {
+            self.binds.push(bind);
+        }
+
+        let prefix = format!("--certificatesresolvers.{}.acme", resolver);
+        self.args.push(format!(
+            "{}.storage={}/{}.json",
+            prefix, CONTAINER_ACME_DIR, resolver
+        ));
+        if let Some(email) = &acme.email {
+            self.args.push(format!("{}.email={}", prefix, email));
+        }
+        if let Some(ca_server) = &acme.ca_server {
+            self.args.push(format!("{}.caserver={}", prefix, ca_server));
+        }
+        self.args.push(match acme.challenge {
+            Challenge::Http => format!("{}.httpchallenge.entrypoint=web", prefix),
+            Challenge::TlsAlpn => format!("{}.tlschallenge=true", prefix),
+        });
+
+        if let Some(ca_cert) = &acme.ca_cert {
+            let mounted = format!("{}/{}.pem", CONTAINER_CA_DIR, resolver);
+            self.binds.push(format!(
+                "{}:{}:ro",
+                std::path::absolute(ca_cert)?.display(),
+                mounted
+            ));
+            // The ACME client trusts these on top of the system roots
+            match self
+                .env
+                .iter_mut()
+                .find(|var| var.starts_with("LEGO_CA_CERTIFICATES="))
+            {
+                Some(var) => {
+                    var.push(':');
+                    var.push_str(&mounted);
+                }
+                None => self.env.push(format!("LEGO_CA_CERTIFICATES={}", mounted)),
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The parts of `docker inspect` that [`Spec`] controls
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct Container {
+    config: ContainerConfig,
+    host_config: HostConfig,
+    state: ContainerState,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct ContainerConfig {
+    image: String,
+    cmd: Option<Vec<String>>,
+    env: Option<Vec<String>>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct HostConfig {
+    binds: Option<Vec<String>>,
+    network_mode: String,
+    port_bindings: Option<HashMap<String, Option<Vec<PortBinding>>>>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct PortBinding {
+    host_port: String,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct ContainerState {
+    running: bool,
+}
+
+impl Spec {
+    /// First difference between `container` and this spec
+    fn diff(&self, container: &Container) -> Option<String> {
+        if container.config.image != self.image {
+            return Some(format!(
+                "image {} -> {}",
+                container.config.image, self.image
+            ));
+        }
+        for (host, port) in &self.ports {
+            let published: Vec<&str> = container
+                .host_config
+                .port_bindings
+                .as_ref()
+                .and_then(|bindings| bindings.get(&format!("{}/tcp", port)))
+                .and_then(Option::as_ref)
+                .into_iter()
+                .flatten()
+                .map(|b| b.host_port.as_str())
+                .collect();
+            if published != [host.to_string().as_str()] {
+                return Some(format!("port {} -> {}", published.join(","), host));
+            }
+        }
+        if container.host_config.network_mode != self.network {
+            return Some(format!(
+                "network {} -> {}",
+                container.host_config.network_mode, self.network
+            ));
+        }
+        if container.host_config.binds.as_ref() != Some(&self.binds) {
+            return Some("mounts".to_string());
+        }
+        if container.config.cmd.as_ref() != Some(&self.args) {
+            return Some("flags".to_string());
+        }
+        // The image adds variables of its own
+        let env = container.config.env.as_deref().unwrap_or_default();
+        if !self.env.iter().all(|var| env.contains(var)) {
+            return Some("environment".to_string());
+        }
+        None
+    }
+}
+
+async fn inspect(name: &str) -> Result<Option<Container>> {
+    match docker::docker(&["inspect", "--type", "container", name]).await {
+        Ok(output) => {
+            let mut found: Vec<Container> = serde_json::from_str(&output.stdout)
+                .context("failed to parse docker inspect output")?;
+            Ok(found.pop())
+        }
+        // No such container
+        Err(CommandError::Failed { .. }) => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+async fn run(spec: &Spec) -> Result<()> {
+    let ports: Vec<String> = spec
+        .ports
+        .iter()
+        .map(|(host, port)| format!("{}:{}", host, port))
+        .collect();
+    let mut args = vec![
+        "run",
+        "-d",
+        "--name",
+        &spec.name,
+        "--network",
+        &spec.network,
+        "--restart",
+        "unless-stopped",
+    ];
+    for port in &ports {
+        args.extend(["-p", port]);
+    }
+    for bind in &spec.binds {
+        args.extend(["-v", bind]);
+    }
+    for var in &spec.env {
+        args.extend(["-e", var]);
+    }
+    args.push(&spec.image);
+    args.extend(spec.args.iter().map(String::as_str));
+
+    docker::docker(&args)
+        .await
+        .context("failed to start traefik")?;
+    Ok(())
+}
+
+fn container_name(app_name: &str) -> String {
+    format!("rollploy-{}-traefik", app_name)
+}
+
+/// Dynamic config directory of the edge proxy, one routes file per app
+fn edge_dir() -> PathBuf {
+    dirs::state_dir()
+        .unwrap_or_else(|| PathBuf::from("/var/lib"))
+        .join("rollploy-edge")
+}
+
+/// The Traefik container that serves an app and how rollploy routes to it
+pub struct Proxy {
+    container: String,
+    routes_file: PathBuf,
+    rule: String,
+    /// None leaves the order to Traefik's rule length, so longer path
+    /// prefixes of other apps on the edge win
+    priority: Option<u32>,
+    /// `tls` of the router on the `websecure` entrypoint, None without one
+    tls: Option<Value>,
+    /// PEM certificate and key, inlined into the routes file
+    certificate: Option<(String, String)>,
+    /// Redirect `web` to HTTPS on this port
+    redirect: Option<u16>,
+}
+
+impl Proxy {
+    pub fn new(
+        app_name: &str,
+        local_path: &Path,
+        entry: &Entry,
+        tls: Option<&Tls>,
+    ) -> Result<Self> {
+        let mut proxy = match entry {
+            Entry::Port(_) => Self {
+                container: container_name(app_name),
+                routes_file: local_path.join(DYNAMIC_DIR).join(ROUTES_FILE),
+                rule: "PathPrefix(`/`)".to_string(),
+                priority: Some(ROUTE_PRIORITY),
+                tls: None,
+                certificate: None,
+                redirect: None,
+            },
+            Entry::Edge { host, path_prefix } => {
+                let rule = [
+                    host.as_ref().map(|host| format!("Host(`{}`)", host)),
+                    path_prefix
+                        .as_ref()
+                        .map(|prefix| format!("PathPrefix(`{}`)", prefix)),
+                ]
+                .into_iter()
+                .flatten()
+                .collect::<Vec<_>>()
+                .join(" && ");
+                Self {
+                    container: EDGE_CONTAINER.to_string(),
+                    routes_file: edge_dir().join(format!("{}.yml", app_name)),
+                    rule,
+                    priority: None,
+                    // Traefik's default certificate until TLS is configured
+                    tls: Some(json!({})),
+                    certificate: None,
+                    redirect: None,
+                }
+            }
+        };
+
+        let Some(tls) = tls else {
+            return Ok(proxy);
+        };
+        let mut router_tls = json!({});
+        if tls.acme.is_some() {
+            router_tls["certResolver"] = json!(app_name);
+        }
+        if let Some((main, sans)) = tls.domains.split_first() {
+            router_tls["domains"] = json!([{ "main": main, "sans": sans }]);
+        }
+        proxy.tls = Some(router_tls);
+        if let (Some(cert), Some(key)) = (&tls.cert, &tls.key) {
+            let read = |path: &PathBuf| {
+                std::fs::read_to_string(path)
+                    .with_context(|| format!("failed to read {}", path.display()))
+            };
+            proxy.certificate = Some((read(cert)?, read(key)?));
+        }
+        if tls.redirect {
+            proxy.redirect = Some(match entry {
+                Entry::Port(_) => tls.port,
+                Entry::Edge { .. } => 443,
+            });
+        }
+        Ok(proxy)
+    }
+
+    /// Names of the routers in the routes file
+    fn routers(&self, app_name: &str) -> Vec<String> {
+        let mut routers = vec![app_name.to_string()];
+        if self.tls.is_some() {
+            routers.push(format!("{}-tls", app_name));
+        }
+        routers
+    }
+}
+
+/// Containers of one slot that traffic can be routed to
+pub struct Backend {
+    /// Compose project of the slot
+    pub project: String,
+    /// Container URLs, by container name as resolved by Docker's DNS on the app network
+    pub servers: Vec<String>,
+}
+
+impl Backend {
+    pub fn new(project: &str, containers: &[String], port: u16) -> Self {
+        Self {
+            project: project.to_string(),
+            servers: containers
+                .iter()
+                .map(|name| format!("http://{}:{}", name, port))
+                .collect(),
+        }
+    }
+}
+
+/// Route all traffic matching the proxy's rule to `backends`, split by
+/// their weights. Backends with weight 0 get nothing.
+pub fn write_routes(proxy: &Proxy, app_name: &str, backends: &[(&Backend, u8)]) -> Result<()> {
+    let mut services = serde_json::Map::new();
+    let mut weighted = Vec::new();
+    for (backend, weight) in backends.iter().filter(|(_, weight)| *weight > 0) {
+        let servers: Vec<_> = backend
+            .servers
+            .iter()
+            .map(|url| json!({ "url": url }))
+            .collect();
+        services.insert(
+            backend.project.clone(),
+            json!({ "loadBalancer": { "servers": servers } }),
+        );
+        weighted.push(json!({ "name": &backend.project, "weight": weight }));
+    }
+    services.insert(
+        app_name.to_string(),
+        json!({ "weighted": { "services": weighted } }),
+    );
+
+    let mut routers = serde_json::Map::new();
+    let mut middlewares = serde_json::Map::new();
+    let mut web = json!({
+        "entryPoints": ["web"],
+        "rule": &proxy.rule,
+        "service": app_name,
+    });
+    if let Some(port) = proxy.redirect {
+        let name = format!("{}-https", app_name);
+        let mut redirect = json!({ "scheme": "https", "permanent": true });
+        if port != 443 {
+            redirect["port"] = json!(port.to_string());
+        }
+        middlewares.insert(name.clone(), json!({ "redirectScheme": redirect }));
+        web["middlewares"] = json!([name]);
+    }
+    routers.insert(app_name.to_string(), web);
+    if let Some(tls) = &proxy.tls {
+        routers.insert(
+            format!("{}-tls", app_name),
+            json!({
+                "entryPoints": ["websecure"],
+                "rule": &proxy.rule,
+                "service": app_name,
+                "tls": tls,
+            }),
+        );
+    }
+    if let Some(priority) = proxy.priority {
+        for router in routers.values_mut() {
+            router["priority"] = json!(priority);
+        }
+    }
+
+    let mut config = json!({
+        "http": {
+            "routers": routers,
+            "services": services,
+        }
+    });
+    if !middlewares.is_empty() {
+        config["http"]["middlewares"] = Value::Object(middlewares);
+    }
+    if let Some((cert, key)) = &proxy.certificate {
+        config["tls"] = json!({ "certificates": [{ "certFile": cert, "keyFile": key }] });
+    }
+
+    let content = serde_yaml::to_string(&config).context("failed to serialize traefik config")?;
+    // Traefik watches the directory, so it must never see a half-written file
+    let tmp = proxy.routes_file.with_extension("yml.tmp");
+    // May hold a private key
+    std::fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(&tmp)
+        .and_then(|mut file| file.write_all(content.as_bytes()))
+        .context("failed to write traefik config")?;
+    std::fs::rename(&tmp, &proxy.routes_file).context("failed to write traefik config")
+}
+
+/// Hand routing back to the routers from labels
+pub fn clear_routes(proxy: &Proxy) -> Result<()> {
+    match std::fs::remove_file(&proxy.routes_file) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+            Err(e).context("failed to remove traefik config")
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Wait until Traefik's API shows the routers from [`write_routes`] enabled
+/// and sending traffic to exactly `backends`
+pub async fn verify_routes(
+    proxy: &Proxy,
+    app_name: &str,
+    network: &str,
+    backends: &[(&Backend, u8)],
+) -> Result<()> {
+    let ip = docker::container_ip(&proxy.container, network).await?;
+    let api = http::Target::Tcp(SocketAddr::new(ip, API_PORT));
+    let routers = proxy.routers(app_name);
+    let deadline = Instant::now() + VERIFY_TIMEOUT;
+    loop {
+        let mismatch = match check_routes(&api, app_name, &routers, backends).await {
+            Ok(None) => return Ok(()),
+            Ok(Some(mismatch)) => mismatch,
+            Err(e) => format!("{:#}", e),
+        };
+        if Instant::now() > deadline {
+            bail!(
+                "traefik did not pick up the routes for {}: {}",
+                app_name,
+                mismatch
+            );
+        }
+        sleep(Duration::from_millis(500)).await;
+    }
+}
+
+/// Compare the live config to the desired one, describing the first difference
+async fn check_routes(
+    api: &http::Target,
+    app_name: &str,
+    routers: &[String],
+    backends: &[(&Backend, u8)],
+) -> Result<Option<String>> {
+    for name in routers {
+        let Some(router) = get(api, &format!("/api/http/routers/{}@file", name)).await? else {
+            return Ok(Some(format!("router {} not loaded", name)));
+        };
+        if router["status"] != "enabled" {
+            return Ok(Some(format!("router {} is {}", name, router["status"])));
+        }
+    }
+
+    let Some(service) = get(api, &format!("/api/http/services/{}@file", app_name)).await? else {
+        return Ok(Some("service not loaded".to_string()));
+    };
+    let live: BTreeMap<String, u64> = service["weighted"]["services"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .map(|s| {
+            let name = s["name"].as_str().unwrap_or_default();
+            let name = name.trim_end_matches("@file").to_string();
+            (name, s["weight"].as_u64().unwrap_or(1))
+        })
+        .collect();
+    let desired: BTreeMap<String, u64> = backends
+        .iter()
+        .filter(|(_, weight)| *weight > 0)
+        .map(|(backend, weight)| (backend.project.clone(), u64::from(*weight)))
+        .collect();
+    if live != desired {
+        return Ok(Some(format!("weights are {:?}, not {:?}", live, desired)));
+    }
+
+    for (backend, _) in backends.iter().filter(|(_, weight)| *weight > 0) {
+        let path = format!("/api/http/services/{}@file", backend.project);
+        let Some(service) = get(api, &path).await? else {
+            return Ok(Some(format!("service {} not loaded", backend.project)));
+        };
+        let mut live: Vec<&str> = service["loadBalancer"]["servers"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .filter_map(|s| s["url"].as_str())
+            .collect();
+        let mut desired: Vec<&str> = backend.servers.iter().map(String::as_str).collect();
+        live.sort_unstable();
+        desired.sort_unstable();
+        if live != desired {
+            return Ok(Some(format!("{} servers are {:?}", backend.project, live)));
+        }
+    }
+    Ok(None)
+}
+
+/// GET a JSON document from the API, or None if it doesn't exist (yet)
+async fn get(api: &http::Target, path: &str) -> Result<Option<Value>> {
+    let response = api_get(api, path).await?;
+    match response.status {
+        200 => serde_json::from_str(&response.body)
+            .map(Some)
+            .with_context(|| format!("invalid JSON from traefik API {}", path)),
+        404 => Ok(None),
+        status => bail!("traefik API {} returned {}", path, status),
+    }
+}
+
+/// GET from the API, giving up on a Traefik or network that stalls
+async fn api_get(api: &http::Target, path: &str) -> Result<http::Response> {
+    timeout(API_TIMEOUT, http::request(api, "GET", path))
+        .await
+        .unwrap_or_else(|_| {
+            Err(anyhow!(
+                "traefik API {} timed out after {}s",
+                path,
+                API_TIMEOUT.as_secs()
+            ))
+        })
+}
@@ -0,0 +1,254 @@
+use crate::actors::supervisor::{Message, Push};
+use anyhow::{Context, Result};
+use axum::body::Bytes;
+use axum::extract::{DefaultBodyLimit, State};
+use axum::http::{HeaderMap, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::routing::post;
+use axum::{Json, Router};
+use hmac::{Hmac, Mac};
+use ractor::rpc::CallResult;
+use ractor::ActorRef;
+use serde_json::Value;
+use sha2::Sha256;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use subtle::ConstantTimeEq;
+use tracing::{info, warn};
+
+const SUPERVISOR: &str = "supervisor";
+const CALL_TIMEOUT: Duration = Duration::from_secs(5);
+/// GitHub sends payloads of up to 25 MB
+const MAX_BODY: usize = 25 * 1024 * 1024;
+
+/// Bind the listener and accept push webhooks in the background
+pub async fn start(addr: SocketAddr, secret: String) -> Result<()> {
+    let app = Router::new()
+        .route("/webhook", post(receive))
+        .layer(DefaultBodyLimit::max(MAX_BODY))
+        .with_state(Arc::new(secret));
+
+    let listener = tokio::net::TcpListener::bind(addr)
+        .await
+        .with_context(|| format!("failed to bind {}", addr))?;
+    info!(addr = %addr, "webhook receiver listening");
+    tokio::spawn(async move {
+        if let Err(e) = axum::serve(listener, app).await {
+            warn!(error = %e, "webhook receiver stopped");
+        }
+    });
+
+    Ok(())
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Provider {
+    GitHub,
+    GitLab,
+    Gitea,
+}
+
+impl Provider {
+    /// Gitea also sends GitHub's headers, so it is checked first
+    fn detect(headers: &HeaderMap) -> Option<Self> {
+        if headers.contains_key("x-gitea-event") {
+            Some(Provider::Gitea)
+        } else if headers.contains_key("x-gitlab-event") {
+            Some(Provider::GitLab)
+        } else if headers.contains_key("x-github-event") {
+            Some(Provider::GitHub)
+        } else {
+            None
+        }
+    }
+
+    fn event<'a>(&self, headers: &'a HeaderMap) -> &'a str {
+        let name = match self {
+            Provider::GitHub => "x-github-event",
+            Provider::GitLab => "x-gitlab-event",
+            Provider::Gitea => "x-gitea-event",
+        };
+        header(headers, name).unwrap_or_default()
+    }
+
+    fn is_push(&self, event: &str) -> bool {
+        match self {
+            Provider::GitHub | Provider::Gitea => event == "push",
+            Provider::GitLab => event == "Push Hook" || event == "Tag Push Hook",
+        }
+    }
+
+    fn verify(&self, headers: &HeaderMap, body: &[u8], secret: &str) -> bool {
+        match self {
+            Provider::GitHub => header(headers, "x-hub-signature-256")
+                .and_then(|sig| sig.strip_prefix("sha256="))
+                .is_some_and(|sig| hmac_matches(secret, body, sig)),
+            Provider::Gitea => header(headers, "x-gitea-signature")
+                .is_some_and(|sig| hmac_matches(secret, body, sig)),
+            // GitLab sends the secret itself rather than a signature
+            Provider::GitLab => header(headers, "x-gitlab-token")
+                .is_some_and(|token| token.as_bytes().ct_eq(secret.as_bytes()).into()),
+        }
+    }
+}
+
+enum WebhookError {
+    Unrecognized,
+    BadSignature,
+    BadPayload(String),
+    Unavailable(String),
+}
+
+impl IntoResponse for WebhookError {
+    fn into_response(self) -> Response {
+        let (code, error) = match self {
+            WebhookError::Unrecognized => (
+                StatusCode::BAD_REQUEST,
+                "not a GitHub, GitLab or Gitea webhook".to_string(),
+            ),
+            WebhookError::BadSignature => {
+                (StatusCode::UNAUTHORIZED, "invalid signature".to_string())
+            }
+            WebhookError::BadPayload(reason) => (StatusCode::BAD_REQUEST, reason),
+            WebhookError::Unavailable(reason) => (StatusCode::SERVICE_UNAVAILABLE, reason),
+        };
+        (code, Json(serde_json::json!({ "error": error }))).into_response()
+    }
+}
+
+async fn receive(
+    State(secret): State<Arc<String>>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Result<Response, WebhookError> {
+    let provider = Provider::detect(&headers).ok_or(WebhookError::Unrecognized)?;
+    if !provider.verify(&headers, &body, &secret) {
+        warn!(provider = ?provider, "rejected webhook with invalid signature");
+        return Err(WebhookError::BadSignature);
+    }
+
+    // Pings and other events are acknowledged so the sender shows them as delivered
+    let event = provider.event(&headers);
+    if !provider.is_push(event) {
+        return Ok(Json(serde_json::json!({ "ignored": event })).into_response());
+    }
+
+    let push = parse(&body)?;
+    let supervisor = ActorRef::<Message>::where_is(SUPERVISOR.to_string())
+        .ok_or_else(|| WebhookError::Unavailable("not running yet".to_string()))?;
+    match supervisor
+        .call(|reply| Message::Push(push, reply), Some(CALL_TIMEOUT))
+        .await
+    {
+        Ok(CallResult::Success(triggered)) => Ok((
+            StatusCode::ACCEPTED,
+            Json(serde_json::json!({ "triggered": triggered })),
+        )
+            .into_response()),
+        Ok(CallResult::Timeout) => Err(WebhookError::Unavailable(
+            "supervisor is busy, try again later".to_string(),
+        )),
+        Ok(CallResult::SenderError) | Err(_) => {
+            Err(WebhookError::Unavailable("shutting down".to_string()))
+        }
+    }
+}
+
+/// Pull the ref and repository URLs out of a push payload. GitHub and Gitea
+/// describe the repository under `repository`, GitLab under `project`.
+fn parse(body: &[u8]) -> Result<Push, WebhookError> {
+    let payload: Value = serde_json::from_slice(body)
+        .map_err(|e| WebhookError::BadPayload(format!("invalid JSON: {}", e)))?;
+
+    let git_ref = payload["ref"]
+        .as_str()
+        .ok_or_else(|| WebhookError::BadPayload("missing ref".to_string()))?
+        .to_string();
+
+    let mut repos = Vec::new();
+    for section in ["repository", "project"] {
+        for key in [
+            "clone_url",
+            "ssh_url",
+            "html_url",
+            "git_http_url",
+            "git_ssh_url",
+            "web_url",
+        ] {
+            if let Some(url) = payload[section][key].as_str() {
+                repos.push(url.to_string());
+            }
+        }
+    }
+    if repos.is_empty() {
+        return Err(WebhookError::BadPayload(
+            "missing repository URL".to_string(),
+        ));
+    }
+
+    Ok(Push { repos, git_ref })
+}
+
+fn hmac_matches(secret: &str, body: &[u8], signature: &str) -> bool {
+    let Ok(signature) = hex::decode(signature) else {
+        return false;
+    };
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts keys of any size");
+    mac.update(body);
+    mac.verify_slice(&signature).is_ok()
+}
+
+fn header<'a>(headers: &'a HeaderMap, name: &str) -> Option<&'a str> {
+    headers.get(name).and_then(|v| v.to_str().ok())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::HeaderValue;
+
+    // Example from GitHub's webhook documentation
+    const SECRET: &str = "It's a Secret to Everybody";
+    const BODY: &[u8] = b"Hello, World!";
+    const SIGNATURE: &str = "757107ea0eb2509fc211221cce984b8a37570b6d7586c22c46f4379c8b043e17";
+
+    fn headers(name: &'static str, value: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(name, HeaderValue::from_str(value).unwrap());
+        headers
+    }
+
+    #[test]
+    fn github_signature() {
+        let valid = headers("x-hub-signature-256", &format!("sha256={}", SIGNATURE));
+        assert!(Provider::GitHub.verify(&valid, BODY, SECRET));
+        assert!(!Provider::GitHub.verify(&valid, b"Hello, World?", SECRET));
+        assert!(!Provider::GitHub.verify(&valid, BODY, "another secret"));
+
+        let unprefixed = headers("x-hub-signature-256", SIGNATURE);
+        assert!(!Provider::GitHub.verify(&unprefixed, BODY, SECRET));
+        assert!(!Provider::GitHub.verify(&HeaderMap::new(), BODY, SECRET));
+    }
+
+    #[test]
+    fn gitea_signature() {
+        let valid = headers("x-gitea-signature", SIGNATURE);
+        assert!(Provider::Gitea.verify(&valid, BODY, SECRET));
+        assert!(!Provider::Gitea.verify(&valid, BODY, "another secret"));
+
+        let garbled = headers("x-gitea-signature", "not hex");
+        assert!(!Provider::Gitea.verify(&garbled, BODY, SECRET));
+        let truncated = headers("x-gitea-signature", &SIGNATURE[..32]);
+        assert!(!Provider::Gitea.verify(&truncated, BODY, SECRET));
+    }
+
+    #[test]
+    fn gitlab_token() {
+        assert!(Provider::GitLab.verify(&headers("x-gitlab-token", SECRET), BODY, SECRET));
+        assert!(!Provider::GitLab.verify(&headers("x-gitlab-token", "guess"), BODY, SECRET));
+        assert!(!Provider::GitLab.verify(&headers("x-gitlab-token", ""), BODY, SECRET));
+        assert!(!Provider::GitLab.verify(&HeaderMap::new(), BODY, SECRET));
+    }
+}